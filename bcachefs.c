@@ -90,6 +90,7 @@ static void usage(void)
 	     "  dump                     Dump filesystem metadata to a qcow2 image\n"
 #ifndef BCACHEFS_NO_RUST
 	     "  list                     List filesystem metadata in textual form\n"
+	     "  debug                    Run library operations, for testing\n"
 #endif
 	     "  list_journal             List contents of journal\n"
 	     "\n"
@@ -265,6 +266,8 @@ int main(int argc, char *argv[])
 #ifndef BCACHEFS_NO_RUST
 	if (!strcmp(cmd, "list"))
 		return cmd_list(argc, argv);
+	if (!strcmp(cmd, "debug"))
+		return cmd_debug(argc, argv);
 #endif
 	if (!strcmp(cmd, "list_journal"))
 		return cmd_list_journal(argc, argv);
@@ -62,5 +62,6 @@ int cmd_subvolume_snapshot(int argc, char *argv[]);
 int cmd_fusemount(int argc, char *argv[]);
 int cmd_mount(int argc, char *argv[]);
 int cmd_completions(int argc, char *argv[]);
+int cmd_debug(int argc, char *argv[]);
 
 #endif /* _CMDS_H */
@@ -12,6 +12,7 @@
 
 struct bio_set;
 struct bio;
+struct blkdev_backend;
 typedef void (bio_end_io_t) (struct bio *);
 
 #define BDEVNAME_SIZE	32
@@ -41,6 +42,10 @@ struct block_device {
 	int			bd_fd;
 	int			bd_sync_fd;
 	int			bd_buffered_fd;
+	/* Not backed by a file or block device - see blkdev_backend_register(): */
+	const struct blkdev_backend *bd_backend;
+	/* Wall clock time the last FUA write completed, in ns, or 0: */
+	u64			bd_last_flush_time;
 };
 
 #define bdev_kobj(_bdev) (&((_bdev)->kobj))
@@ -95,6 +95,22 @@ struct block_device *blkdev_get_by_path(const char *path, blk_mode_t mode,
 					void *holder, const struct blk_holder_ops *hop);
 int lookup_bdev(const char *path, dev_t *);
 
+/*
+ * Devices backed by something other than a file or block device, e.g. an in
+ * memory image for testing: once registered, blkdev_get_by_path() on @name
+ * opens the backend; reads and writes return bytes done or -errno.
+ */
+struct blkdev_backend {
+	void		*private;
+	ssize_t		(*read)(void *private, void *buf, size_t len, u64 offset);
+	ssize_t		(*write)(void *private, const void *buf, size_t len, u64 offset);
+	/* in bytes: */
+	u64		(*size)(void *private);
+};
+
+int blkdev_backend_register(const char *name, const struct blkdev_backend *);
+void blkdev_backend_unregister(const char *name);
+
 struct super_block {
 	void			*s_fs_info;
 };
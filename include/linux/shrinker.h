@@ -21,6 +21,8 @@ struct shrinker {
 
 	int seeks;	/* seeks to recreate an obj */
 	long batch;	/* reclaim batch size, 0 = default */
+	/* Shrunk to this many objects even without memory pressure, 0 for no limit: */
+	unsigned long limit;
 	struct list_head list;
 };
 
@@ -17,14 +17,29 @@
 
 #include "libbcachefs.h"
 #include "crypto.h"
+#include "libbcachefs/alloc_background.h"
+#include "libbcachefs/alloc_foreground.h"
 #include "libbcachefs/bcachefs_format.h"
 #include "libbcachefs/btree_cache.h"
+#include "libbcachefs/btree_gc.h"
+#include "libbcachefs/btree_update.h"
+#include "libbcachefs/buckets.h"
 #include "libbcachefs/checksum.h"
+#include "libbcachefs/dirent.h"
 #include "libbcachefs/disk_groups.h"
+#include "libbcachefs/fs-common.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/io_misc.h"
+#include "libbcachefs/io_read.h"
+#include "libbcachefs/io_write.h"
+#include "libbcachefs/journal_reclaim.h"
 #include "libbcachefs/journal_seq_blacklist.h"
+#include "libbcachefs/logged_ops.h"
 #include "libbcachefs/opts.h"
+#include "libbcachefs/rebalance.h"
 #include "libbcachefs/replicas.h"
 #include "libbcachefs/super-io.h"
+#include "libbcachefs/xattr.h"
 #include "tools-util.h"
 
 #define NSEC_PER_SEC	1000000000L
@@ -735,3 +750,737 @@ dev_names bchu_fs_get_devices(struct bchfs_handle fs)
 
 	return devs;
 }
+
+/* Synchronous file reads and writes, for the Rust bindings: */
+
+static void bch2_read_file_endio(struct bio *bio)
+{
+	closure_put(bio->bi_private);
+}
+
+/*
+ * Read @len bytes at @offset from a file, through the normal read path - so
+ * holes read as zeroes, and compressed and reflinked extents are handled.
+ * @buf, @offset and @len must be block aligned.
+ */
+int bch2_read_file(struct bch_fs *c, subvol_inum inum, u64 offset,
+		   void *buf, size_t len)
+{
+	struct bch_inode_unpacked inode;
+	struct bch_io_opts io_opts;
+	struct bch_read_bio rbio;
+	struct bio_vec bv;
+	struct closure cl;
+	int ret;
+
+	if ((offset | len | (unsigned long) buf) & (block_bytes(c) - 1))
+		return -EINVAL;
+
+	ret = bch2_inode_find_by_inum(c, inum, &inode);
+	if (ret)
+		return ret;
+
+	bch2_inode_opts_get(&io_opts, c, &inode);
+
+	bio_init(&rbio.bio, NULL, &bv, 1, 0);
+	rbio.bio.bi_iter.bi_size	= len;
+	rbio.bio.bi_iter.bi_sector	= offset >> 9;
+	bv.bv_page			= buf;
+	bv.bv_len			= len;
+	bv.bv_offset			= 0;
+	bio_set_op_attrs(&rbio.bio, REQ_OP_READ, REQ_SYNC);
+
+	closure_init_stack(&cl);
+
+	closure_get(&cl);
+	rbio.bio.bi_end_io		= bch2_read_file_endio;
+	rbio.bio.bi_private		= &cl;
+
+	bch2_read(c, rbio_init(&rbio.bio, io_opts), inum);
+
+	closure_sync(&cl);
+
+	return blk_status_to_errno(rbio.bio.bi_status);
+}
+
+/*
+ * Read each pointer of extent @k straight from its device - bypassing the read
+ * path, which would retry from another replica - and check it against the
+ * stored checksum. Sets bits in @bad for devices whose data doesn't match, and
+ * in @io_err for devices we couldn't read from:
+ */
+int bch2_extent_verify_checksums(struct bch_fs *c, struct bkey_s_c k,
+				 u64 *bad, u64 *io_err)
+{
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+	int ret = 0;
+
+	*bad = *io_err = 0;
+
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry) {
+		struct bch_dev *ca = bch_dev_bkey_exists(c, p.ptr.dev);
+		size_t bytes = p.crc.compressed_size << 9;
+		struct bch_csum csum;
+		struct bio *bio;
+		void *buf;
+
+		if (p.ptr.unwritten ||
+		    p.crc.csum_type == BCH_CSUM_none ||
+		    (p.ptr.cached && ptr_stale(ca, &p.ptr)))
+			continue;
+
+		if (!bch2_dev_get_ioref(ca, READ)) {
+			*io_err |= BIT_ULL(p.ptr.dev);
+			continue;
+		}
+
+		buf = kvpmalloc(bytes, GFP_KERNEL);
+		if (!buf) {
+			percpu_ref_put(&ca->io_ref);
+			ret = -ENOMEM;
+			break;
+		}
+
+		bio = bio_alloc_bioset(ca->disk_sb.bdev, buf_pages(buf, bytes),
+				       REQ_OP_READ, GFP_KERNEL, &c->bio_read);
+		bio->bi_iter.bi_sector	= p.ptr.offset;
+		bch2_bio_map(bio, buf, bytes);
+
+		if (submit_bio_wait(bio)) {
+			*io_err |= BIT_ULL(p.ptr.dev);
+		} else {
+			csum = bch2_checksum(c, p.crc.csum_type,
+					     extent_nonce(k.k->version, p.crc),
+					     buf, bytes);
+			if (bch2_crc_cmp(csum, p.crc.csum))
+				*bad |= BIT_ULL(p.ptr.dev);
+		}
+
+		bio_put(bio);
+		kvpfree(buf, bytes);
+		percpu_ref_put(&ca->io_ref);
+	}
+
+	return ret;
+}
+
+/*
+ * Buckets are discarded by marking them need_discard, which moves them from the
+ * freespace btree to the need_discard btree, and having the discard worker
+ * issue the discards - it skips buckets that are open or still waiting on a
+ * journal commit, and holds no locks while discarding:
+ */
+static int bch2_bucket_mark_need_discard(struct btree_trans *trans, struct bch_dev *ca,
+					 u64 b, bool *marked)
+{
+	struct btree_iter iter;
+	struct bkey_i_alloc_v4 *a;
+	int ret;
+
+	*marked = false;
+
+	if (bch2_bucket_is_open_safe(trans->c, ca->dev_idx, b))
+		return 0;
+
+	a = bch2_trans_start_alloc_update(trans, &iter, POS(ca->dev_idx, b));
+	ret = PTR_ERR_OR_ZERO(a);
+	if (ret)
+		return ret;
+
+	if (a->v.data_type == BCH_DATA_free) {
+		SET_BCH_ALLOC_V4_NEED_DISCARD(&a->v, true);
+		a->v.data_type = alloc_data_type(a->v, a->v.data_type);
+
+		ret = bch2_trans_update(trans, &iter, &a->k_i, 0);
+		*marked = !ret;
+	}
+
+	bch2_trans_iter_exit(trans, &iter);
+	return ret;
+}
+
+static int bch2_discard_free_extent(struct btree_trans *trans, struct bch_dev *ca,
+				    struct bkey_s_c k, u64 *sectors)
+{
+	/* the high bits of freespace positions are generation bits: */
+	u64 mask = ~(~0ULL << 56);
+	u64 b, end = k.k->p.offset & mask;
+	int ret = 0;
+
+	for (b = bkey_start_offset(k.k) & mask; b < end && !ret; b++) {
+		bool marked;
+
+		ret = commit_do(trans, NULL, NULL, BTREE_INSERT_NOFAIL,
+				bch2_bucket_mark_need_discard(trans, ca, b, &marked));
+		if (!ret && marked)
+			*sectors += ca->mi.bucket_size;
+	}
+
+	return ret;
+}
+
+/*
+ * Discard the free buckets on @ca, as fstrim does, adding the number of
+ * sectors discarded to @sectors; returns once the discard worker has run.
+ * Devices that aren't online, or don't have the discard option set, are
+ * skipped:
+ */
+int bch2_dev_discard_free_space(struct bch_fs *c, struct bch_dev *ca, u64 *sectors)
+{
+	struct btree_trans *trans;
+	struct btree_iter iter;
+	struct bkey_s_c k;
+	int ret;
+
+	if (!ca->mi.discard || c->opts.nochanges)
+		return 0;
+
+	if (!bch2_dev_get_ioref(ca, WRITE))
+		return 0;
+
+	trans = bch2_trans_get(c);
+	ret = for_each_btree_key2_upto(trans, iter, BTREE_ID_freespace,
+			POS(ca->dev_idx, 0), POS(ca->dev_idx, U64_MAX), 0, k,
+		bch2_discard_free_extent(trans, ca, k, sectors));
+	bch2_trans_put(trans);
+
+	/* A run that had already started may have missed what we marked: */
+	flush_work(&c->discard_work);
+	bch2_do_discards(c);
+	flush_work(&c->discard_work);
+
+	percpu_ref_put(&ca->io_ref);
+	return ret;
+}
+
+/*
+ * Write @len bytes at @offset to a file, synchronously, extending the file's
+ * size to @new_i_size if that's bigger. @buf, @offset and @len must be block
+ * aligned. If @target is nonzero, the data is written only to devices in
+ * @target instead of the file's foreground_target.
+ */
+int bch2_write_file(struct bch_fs *c, subvol_inum inum, u64 offset,
+		    const void *buf, size_t len, u64 new_i_size,
+		    unsigned target)
+{
+	struct bch_inode_unpacked inode;
+	struct bch_io_opts io_opts;
+	struct bch_write_op op;
+	struct bio_vec bv;
+	struct closure cl;
+	int ret;
+
+	if ((offset | len | (unsigned long) buf) & (block_bytes(c) - 1))
+		return -EINVAL;
+
+	ret = bch2_inode_find_by_inum(c, inum, &inode);
+	if (ret)
+		return ret;
+
+	bch2_inode_opts_get(&io_opts, c, &inode);
+	if (target)
+		io_opts.foreground_target = target;
+
+	bio_init(&op.wbio.bio, NULL, &bv, 1, 0);
+	op.wbio.bio.bi_iter.bi_size	= len;
+	bv.bv_page			= (void *) buf;
+	bv.bv_len			= len;
+	bv.bv_offset			= 0;
+	bio_set_op_attrs(&op.wbio.bio, REQ_OP_WRITE, REQ_SYNC);
+
+	bch2_write_op_init(&op, c, io_opts);
+	op.target	= io_opts.foreground_target;
+	op.write_point	= writepoint_hashed(0);
+	op.nr_replicas	= io_opts.data_replicas;
+	op.subvol	= inum.subvol;
+	op.pos		= SPOS(inum.inum, offset >> 9, U32_MAX);
+	op.new_i_size	= new_i_size;
+	op.flags	|= BCH_WRITE_SYNC;
+	if (target)
+		op.flags |= BCH_WRITE_ONLY_SPECIFIED_DEVS;
+
+	ret = bch2_disk_reservation_get(c, &op.res, len >> 9,
+					op.nr_replicas, 0);
+	if (ret)
+		return ret;
+
+	closure_init_stack(&cl);
+	closure_call(&op.cl, bch2_write, NULL, &cl);
+	closure_sync(&cl);
+
+	return op.error;
+}
+
+/* Namespace operations, for applying send streams from the Rust bindings: */
+
+/* Subvolume dirents are followed to the root of the subvolume: */
+int bch2_file_lookup(struct bch_fs *c, subvol_inum dir,
+		     const unsigned char *name, unsigned len, subvol_inum *target)
+{
+	struct qstr qstr = QSTR_INIT(name, len);
+	struct bch_inode_unpacked dir_u;
+	struct bch_hash_info hash_info;
+	int ret;
+
+	ret = bch2_inode_find_by_inum(c, dir, &dir_u);
+	if (ret)
+		return ret;
+
+	hash_info = bch2_hash_info_init(c, &dir_u);
+
+	return bch2_dirent_lookup(c, dir, &hash_info, &qstr, target);
+}
+
+/* Owner, permissions and times are left for bch2_file_setattr(): */
+int bch2_file_create(struct bch_fs *c, subvol_inum dir,
+		     const unsigned char *name, unsigned len,
+		     u32 mode, u32 rdev, u64 *inum)
+{
+	struct qstr qstr = QSTR_INIT(name, len);
+	struct bch_inode_unpacked dir_u, new_inode;
+	int ret;
+
+	bch2_inode_init_early(c, &new_inode);
+
+	ret = bch2_trans_do(c, NULL, NULL, 0,
+		bch2_create_trans(trans, dir, &dir_u, &new_inode, &qstr,
+				  0, 0, mode, rdev, NULL, NULL,
+				  (subvol_inum) {}, 0));
+	if (!ret)
+		*inum = new_inode.bi_inum;
+	return ret;
+}
+
+/*
+ * Create a new subvolume in @dir - or if @snapshot_src is nonzero, a snapshot
+ * of that subvolume:
+ */
+int bch2_file_create_subvol(struct bch_fs *c, subvol_inum dir,
+			    const unsigned char *name, unsigned len,
+			    u32 snapshot_src, u32 *subvol, u64 *root)
+{
+	struct qstr qstr = QSTR_INIT(name, len);
+	struct bch_inode_unpacked dir_u, new_inode;
+	int ret;
+
+	bch2_inode_init_early(c, &new_inode);
+
+	if (snapshot_src)
+		down_write(&c->snapshot_create_lock);
+
+	ret = bch2_trans_do(c, NULL, NULL, 0,
+		bch2_create_trans(trans, dir, &dir_u, &new_inode, &qstr,
+				  0, 0, S_IFDIR|0755, 0, NULL, NULL,
+				  (subvol_inum) { snapshot_src, 0 },
+				  snapshot_src ? BCH_CREATE_SNAPSHOT : BCH_CREATE_SUBVOL));
+
+	if (snapshot_src)
+		up_write(&c->snapshot_create_lock);
+
+	if (!ret) {
+		*subvol	= new_inode.bi_subvol;
+		*root	= new_inode.bi_inum;
+	}
+	return ret;
+}
+
+/*
+ * Delete the subvolume @name in @dir, as the subvol destroy ioctl does; the
+ * subvolume's keys are deleted in the background:
+ */
+int bch2_file_delete_subvol(struct bch_fs *c, subvol_inum dir,
+			    const unsigned char *name, unsigned len)
+{
+	struct qstr qstr = QSTR_INIT(name, len);
+	struct bch_inode_unpacked dir_u, inode_u;
+
+	return bch2_trans_do(c, NULL, NULL, BTREE_INSERT_NOFAIL,
+		bch2_unlink_trans(trans, dir, &dir_u, &inode_u, &qstr, true));
+}
+
+int bch2_file_link(struct bch_fs *c, subvol_inum dir,
+		   const unsigned char *name, unsigned len, u64 inum)
+{
+	struct qstr qstr = QSTR_INIT(name, len);
+	struct bch_inode_unpacked dir_u, inode_u;
+
+	return bch2_trans_do(c, NULL, NULL, 0,
+		bch2_link_trans(trans, dir, &dir_u,
+				(subvol_inum) { dir.subvol, inum }, &inode_u,
+				&qstr));
+}
+
+int bch2_file_unlink(struct bch_fs *c, subvol_inum dir,
+		     const unsigned char *name, unsigned len)
+{
+	struct qstr qstr = QSTR_INIT(name, len);
+	struct bch_inode_unpacked dir_u, inode_u;
+
+	return bch2_trans_do(c, NULL, NULL, BTREE_INSERT_NOFAIL,
+		bch2_unlink_trans(trans, dir, &dir_u, &inode_u, &qstr, false));
+}
+
+int bch2_file_rename(struct bch_fs *c,
+		     subvol_inum src_dir, const unsigned char *src, unsigned src_len,
+		     subvol_inum dst_dir, const unsigned char *dst, unsigned dst_len,
+		     bool overwrite)
+{
+	struct qstr src_name = QSTR_INIT(src, src_len);
+	struct qstr dst_name = QSTR_INIT(dst, dst_len);
+	struct bch_inode_unpacked src_dir_u, dst_dir_u;
+	struct bch_inode_unpacked src_inode_u, dst_inode_u;
+
+	return bch2_trans_do(c, NULL, NULL, 0,
+		bch2_rename_trans(trans,
+				  src_dir, &src_dir_u,
+				  dst_dir, &dst_dir_u,
+				  &src_inode_u, &dst_inode_u,
+				  &src_name, &dst_name,
+				  overwrite ? BCH_RENAME_OVERWRITE : BCH_RENAME));
+}
+
+static int bch2_file_setattr_trans(struct btree_trans *trans, subvol_inum inum,
+				   const struct bch_inode_unpacked *src)
+{
+	struct btree_iter iter;
+	struct bch_inode_unpacked u;
+	int ret;
+
+	ret = bch2_inode_peek(trans, &iter, &u, inum, BTREE_ITER_INTENT);
+	if (ret)
+		return ret;
+
+	u.bi_mode	= (u.bi_mode & S_IFMT) | (src->bi_mode & ~S_IFMT);
+	u.bi_uid	= src->bi_uid;
+	u.bi_gid	= src->bi_gid;
+	u.bi_size	= src->bi_size;
+	u.bi_atime	= src->bi_atime;
+	u.bi_mtime	= src->bi_mtime;
+	u.bi_ctime	= src->bi_ctime;
+	u.bi_otime	= src->bi_otime;
+
+	ret = bch2_inode_write(trans, &iter, &u);
+	bch2_trans_iter_exit(trans, &iter);
+	return ret;
+}
+
+/*
+ * Set permissions, owner, size and times from @src; shrinking the file drops
+ * the data past the new end.
+ */
+int bch2_file_setattr(struct bch_fs *c, subvol_inum inum,
+		      const struct bch_inode_unpacked *src)
+{
+	struct bch_inode_unpacked u;
+	u64 i_sectors_delta = 0;
+	int ret;
+
+	ret = bch2_inode_find_by_inum(c, inum, &u);
+	if (ret)
+		return ret;
+
+	if (src->bi_size < u.bi_size) {
+		ret = bch2_truncate(c, inum, src->bi_size, &i_sectors_delta);
+		if (ret)
+			return ret;
+	}
+
+	return bch2_trans_do(c, NULL, NULL, 0,
+		bch2_file_setattr_trans(trans, inum, src));
+}
+
+/*
+ * Log a truncate without doing any of it, as if we crashed right after it was
+ * logged - for testing that recovery finishes it:
+ */
+int bch2_file_truncate_log_only(struct bch_fs *c, subvol_inum inum, u64 new_i_size)
+{
+	struct bkey_i_logged_op_truncate op;
+
+	bkey_logged_op_truncate_init(&op.k_i);
+	op.v.subvol	= cpu_to_le32(inum.subvol);
+	op.v.inum	= cpu_to_le64(inum.inum);
+	op.v.new_i_size	= cpu_to_le64(new_i_size);
+
+	return bch2_trans_run(c, bch2_logged_op_start(trans, &op.k_i));
+}
+
+/*
+ * For repairing link counts: a directory's link count comes from its
+ * subdirectories, so only other inodes can be set this way.
+ *
+ * Counted and set in the same transaction, so that links made or removed
+ * meanwhile aren't missed - at the cost of walking the whole dirents btree in
+ * one transaction, which starts over from the beginning on every transaction
+ * restart. Fine offline or on an idle filesystem; with enough going on it may
+ * not make progress:
+ */
+static int bch2_file_fix_nlink_trans(struct btree_trans *trans, subvol_inum inum,
+				     unsigned *old, unsigned *nlink)
+{
+	struct btree_iter iter;
+	struct bch_inode_unpacked u;
+	struct bkey_s_c k;
+	u32 snapshot;
+	int ret;
+
+	*nlink = 0;
+
+	ret = bch2_subvolume_get_snapshot(trans, inum.subvol, &snapshot);
+	if (ret)
+		return ret;
+
+	for_each_btree_key_norestart(trans, iter, BTREE_ID_dirents,
+				     SPOS(0, 0, snapshot), 0, k, ret) {
+		struct bkey_s_c_dirent d;
+
+		if (k.k->type != KEY_TYPE_dirent)
+			continue;
+
+		d = bkey_s_c_to_dirent(k);
+		if (d.v->d_type != DT_SUBVOL &&
+		    le64_to_cpu(d.v->d_inum) == inum.inum)
+			(*nlink)++;
+	}
+	bch2_trans_iter_exit(trans, &iter);
+	if (ret)
+		return ret;
+
+	ret = bch2_inode_peek(trans, &iter, &u, inum, BTREE_ITER_INTENT);
+	if (ret)
+		return ret;
+
+	if (S_ISDIR(u.bi_mode)) {
+		ret = -EISDIR;
+		goto err;
+	}
+
+	*old = bch2_inode_nlink_get(&u);
+	bch2_inode_nlink_set(&u, *nlink);
+
+	ret = bch2_inode_write(trans, &iter, &u);
+err:
+	bch2_trans_iter_exit(trans, &iter);
+	return ret;
+}
+
+/*
+ * Set a file's link count to the number of dirents pointing to it in its
+ * subvolume, returning the old and new counts:
+ */
+int bch2_file_fix_nlink(struct bch_fs *c, subvol_inum inum,
+			unsigned *old, unsigned *nlink)
+{
+	return bch2_trans_do(c, NULL, NULL, 0,
+		bch2_file_fix_nlink_trans(trans, inum, old, nlink));
+}
+
+static int opt_to_inode_opt(int id)
+{
+	switch (id) {
+#define x(name, ...)				\
+	case Opt_##name: return Inode_opt_##name;
+	BCH_INODE_OPTS()
+#undef  x
+	default:
+		return -1;
+	}
+}
+
+static int bch2_file_opt_set_trans(struct btree_trans *trans, subvol_inum inum,
+				   int id, u64 v)
+{
+	struct btree_iter iter;
+	struct bch_inode_unpacked u;
+	int ret;
+
+	ret = bch2_inode_peek(trans, &iter, &u, inum, BTREE_ITER_INTENT);
+	if (ret)
+		return ret;
+
+	if (v)
+		u.bi_fields_set |= 1U << id;
+	else
+		u.bi_fields_set &= ~(1U << id);
+	bch2_inode_opt_set(&u, id, v);
+
+	ret = bch2_inode_write(trans, &iter, &u);
+	bch2_trans_iter_exit(trans, &iter);
+	return ret;
+}
+
+/*
+ * Set an inode option, as with the bcachefs.* xattrs; @value NULL unsets it.
+ * Inodes created afterwards inherit it from their directory, existing inodes
+ * are unchanged. Project IDs need quota updates, which we don't do here:
+ */
+int bch2_file_opt_set(struct bch_fs *c, subvol_inum inum,
+		      const char *name, const char *value)
+{
+	int opt_id, inode_opt_id, ret;
+	u64 v = 0;
+
+	opt_id = bch2_opt_lookup(name);
+	if (opt_id < 0)
+		return -EINVAL;
+
+	inode_opt_id = opt_to_inode_opt(opt_id);
+	if (inode_opt_id < 0 || inode_opt_id == Inode_opt_project)
+		return -EINVAL;
+
+	if (value) {
+		ret = bch2_opt_parse(c, bch2_opt_table + opt_id, value, &v, NULL) ?:
+			bch2_opt_check_may_set(c, opt_id, v);
+		if (ret < 0)
+			return ret;
+
+		/* stored with a +1 bias, so that 0 means unset: */
+		v++;
+	}
+
+	ret = bch2_trans_do(c, NULL, NULL, 0,
+		bch2_file_opt_set_trans(trans, inum, inode_opt_id, v));
+	if (ret)
+		return ret;
+
+	if (value &&
+	    (opt_id == Opt_background_compression ||
+	     opt_id == Opt_background_target))
+		ret = bch2_set_rebalance_needs_scan(c, inum.inum);
+	return ret;
+}
+
+/* @value NULL removes the xattr: */
+int bch2_file_xattr_set(struct bch_fs *c, subvol_inum inum, int type,
+			const char *name, const void *value, size_t size)
+{
+	struct bch_inode_unpacked inode_u;
+	struct bch_hash_info hash_info;
+	int ret;
+
+	ret = bch2_inode_find_by_inum(c, inum, &inode_u);
+	if (ret)
+		return ret;
+
+	hash_info = bch2_hash_info_init(c, &inode_u);
+
+	return bch2_trans_do(c, NULL, NULL, 0,
+		bch2_xattr_set(trans, inum, &inode_u, &hash_info,
+			       name, value, size, type, 0));
+}
+
+/*
+ * Set a filesystem option on a running filesystem and persist it to the
+ * superblock, as writing to the options directory in sysfs does:
+ */
+int bch2_fs_opt_set(struct bch_fs *c, const char *name, const char *value)
+{
+	const struct bch_option *opt;
+	int id, ret;
+	u64 v;
+
+	id = bch2_opt_lookup(name);
+	if (id < 0)
+		return -EINVAL;
+	opt = bch2_opt_table + id;
+
+	ret = bch2_opt_parse(c, opt, value, &v, NULL) ?:
+		bch2_opt_check_may_set(c, id, v);
+	if (ret < 0)
+		return ret;
+
+	bch2_opt_set_sb(c, opt, v);
+	bch2_opt_set_by_id(&c->opts, id, v);
+
+	if ((id == Opt_background_target ||
+	     id == Opt_background_compression) && v)
+		ret = bch2_set_rebalance_needs_scan(c, 0);
+	return ret;
+}
+
+/* rebalance_wakeup() is inline; -EROFS if the rebalance thread isn't running: */
+int bch2_rebalance_kick(struct bch_fs *c)
+{
+	if (!rcu_access_pointer(c->rebalance.thread))
+		return -BCH_ERR_erofs_no_writes;
+
+	rebalance_wakeup(c);
+	return 0;
+}
+
+/*
+ * Full mark and sweep gc, on a running filesystem: the gc thread doesn't do
+ * this, since gc doesn't see updates sitting in the btree key cache - so we
+ * flush it first, and hold state_lock against anything else going on. Updates
+ * racing with us still aren't safe; this is for when nothing else is writing:
+ */
+int bch2_fs_gc(struct bch_fs *c)
+{
+	int ret;
+
+	down_write(&c->state_lock);
+	bch2_journal_flush_all_pins(&c->journal);
+	ret = bch2_gc(c, false, false);
+	up_write(&c->state_lock);
+	return ret;
+}
+
+/* Inline btree type helpers, for the Rust bindings: */
+bool bch2_btree_id_is_extents(enum btree_id btree)
+{
+	return btree_id_is_extents(btree);
+}
+
+bool bch2_btree_type_has_snapshots(enum btree_id btree)
+{
+	return btree_type_has_snapshots(btree);
+}
+
+/* Leaf key types each btree may hold, as in bkey_methods.c: */
+static const u64 bch2_btree_key_types[] = {
+#define x(name, nr, flags, keys)	[BTREE_ID_##name] = BIT_ULL(KEY_TYPE_deleted)|keys,
+	BCH_BTREE_IDS()
+#undef x
+};
+
+bool bch2_btree_type_has_key_type(enum btree_id btree, enum bch_bkey_type type)
+{
+	return btree < ARRAY_SIZE(bch2_btree_key_types) &&
+		(bch2_btree_key_types[btree] & BIT_ULL(type));
+}
+
+/*
+ * Have the shrinker thread keep the btree node cache to @bytes, 0 for no limit
+ * beyond memory pressure; the reserve, and nodes that can't be freed, are on
+ * top of that:
+ */
+void bch2_btree_cache_set_limit(struct bch_fs *c, u64 bytes)
+{
+	WRITE_ONCE(c->btree_cache.shrink.limit, div64_u64(bytes, btree_bytes(c)));
+}
+
+/*
+ * Call @fn(@p, b, dirty) on every node in the btree node cache, with the cache
+ * locked; roots aren't on the live list, since they can't be reaped:
+ */
+void bch2_btree_cache_for_each(struct bch_fs *c,
+			       void (*fn)(void *, struct btree *, bool), void *p)
+{
+	struct btree_cache *bc = &c->btree_cache;
+	struct btree *b;
+	unsigned i;
+
+	mutex_lock(&bc->lock);
+	for (i = 0; i < btree_id_nr_alive(c); i++) {
+		b = bch2_btree_id_root(c, i)->b;
+		if (b)
+			fn(p, b, btree_node_dirty(b));
+	}
+
+	list_for_each_entry(b, &bc->live, list)
+		if (b->hash_val)
+			fn(p, b, btree_node_dirty(b));
+	mutex_unlock(&bc->lock);
+}
@@ -7,6 +7,7 @@
 #include "libbcachefs/bcachefs_format.h"
 #include "libbcachefs/bcachefs_ioctl.h"
 #include "libbcachefs/opts.h"
+#include "libbcachefs/subvolume_types.h"
 #include "libbcachefs/vstructs.h"
 #include "tools-util.h"
 
@@ -247,4 +248,50 @@ typedef DARRAY(struct dev_name) dev_names;
 
 dev_names bchu_fs_get_devices(struct bchfs_handle);
 
+struct bch_fs;
+struct bch_dev;
+struct bch_inode_unpacked;
+
+int bch2_read_file(struct bch_fs *, subvol_inum, u64, void *, size_t);
+int bch2_write_file(struct bch_fs *, subvol_inum, u64, const void *, size_t, u64, unsigned);
+int bch2_extent_verify_checksums(struct bch_fs *, struct bkey_s_c, u64 *, u64 *);
+int bch2_dev_discard_free_space(struct bch_fs *, struct bch_dev *, u64 *);
+
+int bch2_file_lookup(struct bch_fs *, subvol_inum,
+		     const unsigned char *, unsigned, subvol_inum *);
+int bch2_file_create(struct bch_fs *, subvol_inum,
+		     const unsigned char *, unsigned, u32, u32, u64 *);
+int bch2_file_create_subvol(struct bch_fs *, subvol_inum,
+			    const unsigned char *, unsigned, u32, u32 *, u64 *);
+int bch2_file_delete_subvol(struct bch_fs *, subvol_inum,
+			    const unsigned char *, unsigned);
+int bch2_file_link(struct bch_fs *, subvol_inum,
+		   const unsigned char *, unsigned, u64);
+int bch2_file_unlink(struct bch_fs *, subvol_inum,
+		     const unsigned char *, unsigned);
+int bch2_file_rename(struct bch_fs *,
+		     subvol_inum, const unsigned char *, unsigned,
+		     subvol_inum, const unsigned char *, unsigned, bool);
+int bch2_file_setattr(struct bch_fs *, subvol_inum,
+		      const struct bch_inode_unpacked *);
+int bch2_file_truncate_log_only(struct bch_fs *, subvol_inum, u64);
+int bch2_file_fix_nlink(struct bch_fs *, subvol_inum, unsigned *, unsigned *);
+int bch2_file_opt_set(struct bch_fs *, subvol_inum, const char *, const char *);
+int bch2_file_xattr_set(struct bch_fs *, subvol_inum, int,
+			const char *, const void *, size_t);
+
+int bch2_fs_opt_set(struct bch_fs *, const char *, const char *);
+int bch2_rebalance_kick(struct bch_fs *);
+int bch2_fs_gc(struct bch_fs *);
+
+bool bch2_btree_id_is_extents(enum btree_id);
+bool bch2_btree_type_has_snapshots(enum btree_id);
+bool bch2_btree_type_has_key_type(enum btree_id, enum bch_bkey_type);
+
+void bch2_btree_cache_set_limit(struct bch_fs *, u64);
+
+struct btree;
+void bch2_btree_cache_for_each(struct bch_fs *,
+			       void (*)(void *, struct btree *, bool), void *);
+
 #endif /* _LIBBCACHE_H */
@@ -19,6 +19,8 @@
 #include <linux/completion.h>
 #include <linux/fs.h>
 #include <linux/kthread.h>
+#include <linux/list.h>
+#include <linux/mutex.h>
 
 #include "tools-util.h"
 
@@ -33,6 +35,118 @@ static struct fops *fops;
 static io_context_t aio_ctx;
 static atomic_t running_requests;
 
+struct blkdev_backend_entry {
+	struct list_head	list;
+	char			*name;
+	struct blkdev_backend	backend;
+};
+
+static LIST_HEAD(blkdev_backends);
+static DEFINE_MUTEX(blkdev_backends_lock);
+
+static struct blkdev_backend_entry *blkdev_backend_find(const char *name)
+{
+	struct blkdev_backend_entry *e;
+
+	list_for_each_entry(e, &blkdev_backends, list)
+		if (!strcmp(e->name, name))
+			return e;
+	return NULL;
+}
+
+int blkdev_backend_register(const char *name, const struct blkdev_backend *backend)
+{
+	struct blkdev_backend_entry *e;
+	int ret = 0;
+
+	mutex_lock(&blkdev_backends_lock);
+	if (blkdev_backend_find(name)) {
+		ret = -EEXIST;
+		goto out;
+	}
+
+	e = xmalloc(sizeof(*e));
+	e->name		= strdup(name);
+	e->backend	= *backend;
+	list_add(&e->list, &blkdev_backends);
+out:
+	mutex_unlock(&blkdev_backends_lock);
+	return ret;
+}
+
+/* Devices opened on @name must have been closed: */
+void blkdev_backend_unregister(const char *name)
+{
+	struct blkdev_backend_entry *e;
+
+	mutex_lock(&blkdev_backends_lock);
+	e = blkdev_backend_find(name);
+	if (e) {
+		list_del(&e->list);
+		free(e->name);
+		free(e);
+	}
+	mutex_unlock(&blkdev_backends_lock);
+}
+
+/* Bios that reached the device complete here, so we can note flush times: */
+static void blkdev_bio_endio(struct bio *bio)
+{
+	if (bio_op(bio) == REQ_OP_WRITE &&
+	    (bio->bi_opf & REQ_FUA) &&
+	    !bio->bi_status)
+		WRITE_ONCE(bio->bi_bdev->bd_last_flush_time, ktime_get_real_ns());
+
+	bio_endio(bio);
+}
+
+static int backend_io(const struct blkdev_backend *b, unsigned op,
+		      void *buf, size_t len, u64 offset)
+{
+	while (len) {
+		ssize_t ret = op == REQ_OP_READ
+			? b->read(b->private, buf, len, offset)
+			: b->write(b->private, buf, len, offset);
+		if (ret <= 0)
+			return ret ?: -EIO;
+
+		buf	+= ret;
+		len	-= ret;
+		offset	+= ret;
+	}
+
+	return 0;
+}
+
+static void backend_make_request(struct bio *bio)
+{
+	const struct blkdev_backend *b = bio->bi_bdev->bd_backend;
+	u64 offset = bio->bi_iter.bi_sector << 9;
+	struct bvec_iter iter;
+	struct bio_vec bv;
+
+	switch (bio_op(bio)) {
+	case REQ_OP_READ:
+	case REQ_OP_WRITE:
+		bio_for_each_segment(bv, bio, iter) {
+			if (backend_io(b, bio_op(bio),
+				       page_address(bv.bv_page) + bv.bv_offset,
+				       bv.bv_len, offset)) {
+				bio->bi_status = BLK_STS_IOERR;
+				break;
+			}
+			offset += bv.bv_len;
+		}
+		break;
+	case REQ_OP_FLUSH:
+		break;
+	default:
+		BUG();
+	}
+
+	blkdev_bio_endio(bio);
+}
+
 void generic_make_request(struct bio *bio)
 {
 	struct iovec *iov;
@@ -41,6 +155,11 @@ void generic_make_request(struct bio *bio)
 	ssize_t ret;
 	unsigned i;
 
+	if (bio->bi_bdev->bd_backend) {
+		backend_make_request(bio);
+		return;
+	}
+
 	if (bio->bi_opf & REQ_PREFLUSH) {
 		ret = fdatasync(bio->bi_bdev->bd_fd);
 		if (ret) {
@@ -115,7 +234,22 @@ int blkdev_issue_discard(struct block_device *bdev,
 			 sector_t sector, sector_t nr_sects,
 			 gfp_t gfp_mask)
 {
-	return 0;
+	struct stat statbuf;
+	u64 range[2] = { sector << 9, nr_sects << 9 };
+	int ret;
+
+	if (bdev->bd_backend)
+		return -EOPNOTSUPP;
+
+	ret = fstat(bdev->bd_fd, &statbuf);
+	BUG_ON(ret);
+
+	/* For images in files, punching a hole is the equivalent: */
+	ret = S_ISBLK(statbuf.st_mode)
+		? ioctl(bdev->bd_fd, BLKDISCARD, range)
+		: fallocate(bdev->bd_fd, FALLOC_FL_PUNCH_HOLE|FALLOC_FL_KEEP_SIZE,
+			    range[0], range[1]);
+	return ret ? -errno : 0;
 }
 
 int blkdev_issue_zeroout(struct block_device *bdev,
@@ -132,6 +266,9 @@ unsigned bdev_logical_block_size(struct block_device *bdev)
 	unsigned blksize;
 	int ret;
 
+	if (bdev->bd_backend)
+		return 512;
+
 	ret = fstat(bdev->bd_fd, &statbuf);
 	BUG_ON(ret);
 
@@ -150,6 +287,9 @@ sector_t get_capacity(struct gendisk *disk)
 	u64 bytes;
 	int ret;
 
+	if (bdev->bd_backend)
+		return bdev->bd_backend->size(bdev->bd_backend->private) >> 9;
+
 	ret = fstat(bdev->bd_fd, &statbuf);
 	BUG_ON(ret);
 
@@ -164,6 +304,11 @@ sector_t get_capacity(struct gendisk *disk)
 
 void blkdev_put(struct block_device *bdev, void *holder)
 {
+	if (bdev->bd_backend) {
+		free(bdev);
+		return;
+	}
+
 	fdatasync(bdev->bd_fd);
 	close(bdev->bd_sync_fd);
 	close(bdev->bd_fd);
@@ -174,8 +319,30 @@ struct block_device *blkdev_get_by_path(const char *path, blk_mode_t mode,
 					void *holder, const struct blk_holder_ops *hop)
 {
 	struct block_device *bdev;
+	struct blkdev_backend_entry *backend;
 	int fd, sync_fd, buffered_fd, flags = 0;
 
+	mutex_lock(&blkdev_backends_lock);
+	backend = blkdev_backend_find(path);
+	mutex_unlock(&blkdev_backends_lock);
+
+	if (backend) {
+		bdev = xcalloc(1, sizeof(*bdev));
+
+		strncpy(bdev->name, path, sizeof(bdev->name));
+		bdev->name[sizeof(bdev->name) - 1] = '\0';
+
+		bdev->bd_fd		= -1;
+		bdev->bd_sync_fd	= -1;
+		bdev->bd_buffered_fd	= -1;
+		bdev->bd_backend	= &backend->backend;
+		bdev->bd_holder		= holder;
+		bdev->bd_disk		= &bdev->__bd_disk;
+		bdev->bd_disk->bdi	= &bdev->bd_disk->__bdi;
+		bdev->queue.backing_dev_info = bdev->bd_disk->bdi;
+		return bdev;
+	}
+
 	if ((mode & (BLK_OPEN_READ|BLK_OPEN_WRITE)) == (BLK_OPEN_READ|BLK_OPEN_WRITE))
 		flags = O_RDWR;
 	else if (mode & BLK_OPEN_READ)
@@ -259,7 +426,7 @@ static void sync_check(struct bio *bio, int ret)
 		if (ret)
 			die("fsync error: %s\n", strerror(-ret));
 	}
-	bio_endio(bio);
+	blkdev_bio_endio(bio);
 }
 
 static void sync_init(void) {}
@@ -321,7 +488,7 @@ static int aio_completion_thread(void *arg)
 			if (ev->res != bio->bi_iter.bi_size)
 				bio->bi_status = BLK_STS_IOERR;
 
-			bio_endio(bio);
+			blkdev_bio_endio(bio);
 			atomic_dec(&running_requests);
 		}
 	}
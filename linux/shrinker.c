@@ -1,11 +1,13 @@
 
 #include <stdio.h>
 
+#include <linux/jiffies.h>
 #include <linux/kthread.h>
 #include <linux/list.h>
 #include <linux/mm.h>
 #include <linux/mutex.h>
 #include <linux/shrinker.h>
+#include <linux/time64.h>
 
 #include "tools-util.h"
 
@@ -128,14 +130,52 @@ void run_shrinkers(gfp_t gfp_mask, bool allocation_failed)
 	mutex_unlock(&shrinker_lock);
 }
 
+/* Returns true if any shrinker has a limit, so we should check again soon: */
+static bool run_shrinkers_over_limit(gfp_t gfp_mask)
+{
+	struct shrinker *shrinker;
+	bool limited = false;
+
+	mutex_lock(&shrinker_lock);
+	list_for_each_entry(shrinker, &shrinker_list, list) {
+		struct shrink_control sc = { .gfp_mask	= gfp_mask, };
+		unsigned long limit = READ_ONCE(shrinker->limit), have;
+
+		if (!limit)
+			continue;
+
+		limited = true;
+
+		have = shrinker->count_objects(shrinker, &sc);
+		if (have > limit) {
+			sc.nr_to_scan = have - limit;
+			shrinker->scan_objects(shrinker, &sc);
+		}
+	}
+	mutex_unlock(&shrinker_lock);
+
+	return limited;
+}
+
 static int shrinker_thread(void *arg)
 {
+	unsigned long next_meminfo = jiffies;
+	bool limited = false;
+
 	while (!kthread_should_stop()) {
 		struct timespec to;
 		int v;
 
 		clock_gettime(CLOCK_MONOTONIC, &to);
-		to.tv_sec += 1;
+		if (limited) {
+			to.tv_nsec += NSEC_PER_SEC / 100;
+			if (to.tv_nsec >= NSEC_PER_SEC) {
+				to.tv_sec++;
+				to.tv_nsec -= NSEC_PER_SEC;
+			}
+		} else {
+			to.tv_sec += 1;
+		}
 		__set_current_state(TASK_INTERRUPTIBLE);
 		errno = 0;
 		while ((v = READ_ONCE(current->state)) != TASK_RUNNING &&
@@ -146,7 +186,13 @@ static int shrinker_thread(void *arg)
 			break;
 		if (v != TASK_RUNNING)
 			__set_current_state(TASK_RUNNING);
-		run_shrinkers(GFP_KERNEL, false);
+		limited = run_shrinkers_over_limit(GFP_KERNEL);
+
+		/* Limits may have us waking more often, but meminfo is checked once a second: */
+		if (time_after_eq(jiffies, next_meminfo)) {
+			run_shrinkers(GFP_KERNEL, false);
+			next_meminfo = jiffies + HZ;
+		}
 	}
 
 	return 0;
@@ -45,12 +45,17 @@ fn main() {
         })
         .allowlist_function(".*bch2_.*")
         .allowlist_function("bio_.*")
+        .allowlist_function("blkdev_backend_.*")
         .allowlist_function("derive_passphrase")
         .allowlist_function("request_key")
         .allowlist_function("add_key")
         .allowlist_function("keyctl_search")
         .allowlist_function("match_string")
         .allowlist_function("printbuf.*")
+        .allowlist_function("crc32c")
+        .allowlist_function("crc64_be")
+        .allowlist_function("xxh64_.*")
+        .allowlist_function("crypto_hash_sha256_.*")
         .blocklist_type("bch_extent_ptr")
         .blocklist_type("btree_node")
         .blocklist_type("bch_extent_crc32")
@@ -70,6 +75,7 @@ fn main() {
         .allowlist_type("nonce")
         .no_debug("bch_replicas_padded")
         .newtype_enum("bch_kdf_types")
+        .newtype_enum("bch_errcode")
         .rustified_enum("bch_key_types")
         .opaque_type("gendisk")
         .opaque_type("gc_stripe")
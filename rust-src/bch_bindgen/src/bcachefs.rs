@@ -16,6 +16,23 @@ bitfield! {
     pub struct bch_crypt_flags(u64);
     pub TYPE, _: 4, 0;
 }
+bitfield! {
+    pub struct bch_snapshot_flags(u32);
+    pub DELETED, _: 0;
+    pub SUBVOL, _: 1;
+}
+bitfield! {
+    /* bch_sb.flags[1]: */
+    pub struct bch_sb_flags1(u64);
+    pub BCH_SB_128_BIT_MACS, _: 9;
+    pub BCH_SB_ENCRYPTION_TYPE, _: 13, 10;
+}
+bitfield! {
+    pub struct bch_subvolume_flags(u32);
+    pub RO, _: 0;
+    pub SNAP, _: 1;
+    pub UNLINKED, _: 2;
+}
 use memoffset::offset_of;
 impl bch_sb_field_crypt {
     pub fn scrypt_flags(&self) -> Option<bch_scrypt_flags> {
@@ -3,6 +3,7 @@
 use crate::c;
 use crate::fs::Fs;
 use crate::btree::BtreeIter;
+use crate::errcode::bch_errcode;
 use crate::printbuf_to_formatter;
 use std::fmt;
 use std::marker::PhantomData;
@@ -52,7 +53,7 @@ pub enum BkeyValC<'a> {
 }
 
 impl<'a, 'b> BkeySC<'a> {
-    unsafe fn to_raw(&self) -> c::bkey_s_c {
+    pub(crate) unsafe fn to_raw(&self) -> c::bkey_s_c {
         c::bkey_s_c { k: self.k, v: self.v }
     }
 
@@ -60,6 +61,46 @@ impl<'a, 'b> BkeySC<'a> {
         BkeySCToText { k: self, fs }
     }
 
+    /// The raw bytes of the value, following the (unpacked) key
+    pub fn val_bytes(&self) -> &'a [u8] {
+        let key_u64s = std::mem::size_of::<c::bkey>() / std::mem::size_of::<u64>();
+        let val_u64s = (self.k.u64s as usize).saturating_sub(key_u64s);
+
+        unsafe {
+            std::slice::from_raw_parts(self.v as *const c::bch_val as *const u8,
+                val_u64s * std::mem::size_of::<u64>())
+        }
+    }
+
+    /// Dump the value in the same layout as `xxd`: offset, 16 bytes per line
+    /// in groups of two, then the printable ascii
+    pub fn hexdump(&self) -> String {
+        use std::fmt::Write;
+
+        let mut out = String::new();
+
+        for (i, line) in self.val_bytes().chunks(16).enumerate() {
+            write!(out, "{:08x}:", i * 16).unwrap();
+
+            for j in 0..16 {
+                if j % 2 == 0 {
+                    out.push(' ');
+                }
+                match line.get(j) {
+                    Some(b) => write!(out, "{:02x}", b).unwrap(),
+                    None    => out.push_str("  "),
+                }
+            }
+
+            out.push_str("  ");
+            out.extend(line.iter()
+                .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+            out.push('\n');
+        }
+
+        out
+    }
+
     pub fn v(&'a self) -> BkeyValC {
         let ty: c::bch_bkey_type = unsafe { transmute(self.k.type_ as u32) };
 
@@ -105,12 +146,92 @@ impl<'a, 'b> BkeySC<'a> {
     }
 }
 
+/// A decoded node in the snapshots btree
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotKeyInfo {
+    pub id:         u32,
+    pub tree:       u32,
+    pub parent:     u32,
+    /// Unused slots are 0
+    pub children:   [u32; 2],
+    /// Subvolume pointing to this snapshot, or 0
+    pub subvol:     u32,
+    pub depth:      u32,
+    pub deleted:    bool,
+}
+
+impl<'a> BkeySC<'a> {
+    pub fn as_snapshot(&self) -> Result<SnapshotKeyInfo, bch_errcode> {
+        let s = match self.v() {
+            BkeyValC::snapshot(s)   => s,
+            _                       => return Err(bch_errcode::BCH_ERR_ENOENT_bkey_type_mismatch),
+        };
+        let flags = c::bch_snapshot_flags(u32::from_le(s.flags));
+
+        Ok(SnapshotKeyInfo {
+            id:         self.k.p.offset as u32,
+            tree:       u32::from_le(s.tree),
+            parent:     u32::from_le(s.parent),
+            children:   [u32::from_le(s.children[0]), u32::from_le(s.children[1])],
+            subvol:     u32::from_le(s.subvol),
+            depth:      u32::from_le(s.depth),
+            deleted:    flags.DELETED(),
+        })
+    }
+
+    pub fn to_owned_bkey(&self) -> OwnedBkey {
+        let key_bytes = std::mem::size_of::<c::bkey>();
+        let val = self.val_bytes();
+        let mut buf = vec![0u64; (key_bytes + val.len()) / std::mem::size_of::<u64>()];
+
+        unsafe {
+            let dst = buf.as_mut_ptr() as *mut u8;
+
+            std::ptr::copy_nonoverlapping(self.k as *const c::bkey as *const u8, dst, key_bytes);
+            std::ptr::copy_nonoverlapping(val.as_ptr(), dst.add(key_bytes), val.len());
+        }
+
+        OwnedBkey { buf }
+    }
+}
+
 impl<'a> From<&'a c::bkey_i> for BkeySC<'a> {
     fn from(k: &'a c::bkey_i) -> Self {
         BkeySC { k: &k.k, v: &k.v, iter: PhantomData }
     }
 }
 
+/// A key copied out of the btree, so that it can outlive the iterator it came
+/// from
+#[derive(Clone)]
+pub struct OwnedBkey {
+    /* A bkey_i: the unpacked key, then the value */
+    buf: Vec<u64>,
+}
+
+impl OwnedBkey {
+    pub fn k(&self) -> &c::bkey {
+        &self.as_bkey_i().k
+    }
+
+    fn as_bkey_i(&self) -> &c::bkey_i {
+        unsafe { &*(self.buf.as_ptr() as *const c::bkey_i) }
+    }
+
+    pub fn as_bkey_sc(&self) -> BkeySC<'_> {
+        self.as_bkey_i().into()
+    }
+}
+
+impl fmt::Debug for OwnedBkey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let k = self.k();
+        let (p, ty) = (k.p, k.type_);
+
+        write!(f, "OwnedBkey {{ p: {}, type: {} }}", p, ty)
+    }
+}
+
 pub struct BkeySCToText<'a, 'b> {
     k:  &'a BkeySC<'a>,
     fs: &'b Fs,
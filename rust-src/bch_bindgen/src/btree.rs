@@ -1,17 +1,20 @@
-use crate::SPOS_MAX;
+use crate::{pos, POS_MIN, SPOS_MAX};
 use crate::c;
-use crate::bkey::BkeySC;
+use crate::bkey::{BkeySC, OwnedBkey};
 use crate::fs::Fs;
-use crate::errcode::{bch_errcode, errptr_to_result_c};
+use crate::errcode::{bch_errcode, errcode_to_result, errptr_to_result_c};
 use crate::printbuf_to_formatter;
 use std::fmt;
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
+use std::os::raw::c_void;
+use std::ops::ControlFlow;
 use std::ptr;
+use std::time::Duration;
 use bitflags::bitflags;
 
 pub struct BtreeTrans<'f> {
-    raw:    *mut c::btree_trans,
+    pub(crate) raw: *mut c::btree_trans,
     fs:     PhantomData<&'f Fs>
 }
 
@@ -21,6 +24,351 @@ impl<'f> BtreeTrans<'f> {
             BtreeTrans { raw: &mut *c::__bch2_trans_get(fs.raw, 0), fs: PhantomData }
         }
     }
+
+    /// Reset the transaction after a restart
+    pub fn begin(&self) -> u32 {
+        unsafe { c::bch2_trans_begin(self.raw) }
+    }
+
+    /// Run `f`, restarting the transaction for as long as it returns a
+    /// transaction restart, as with lockrestart_do() in C
+    pub fn lockrestart_do<T, F>(&self, mut f: F) -> Result<T, bch_errcode>
+        where F: FnMut() -> Result<T, bch_errcode> {
+        loop {
+            self.begin();
+
+            match f() {
+                Err(e) if e.is_restart() => continue,
+                r => return r,
+            }
+        }
+    }
+
+    /// Call `f` on each key in `btree` from `start` to `end` (inclusive),
+    /// handling transaction restarts; `f` returns `ControlFlow::Break` to
+    /// stop early
+    pub fn for_each_key<F>(&self,
+        btree:  c::btree_id,
+        start:  c::bpos,
+        end:    c::bpos,
+        flags:  BtreeIterFlags,
+        mut f:  F) -> Result<(), bch_errcode>
+        where F: FnMut(BkeySC) -> ControlFlow<()> {
+        let mut iter = BtreeIter::new(self, btree, start, flags);
+
+        while let Some(k) = iter.peek_and_restart()? {
+            if k.k.p > end {
+                break;
+            }
+
+            if f(k).is_break() {
+                break;
+            }
+            iter.advance();
+        }
+
+        Ok(())
+    }
+
+    /// Locks currently held by this transaction, for debugging lock ordering
+    pub fn held_locks(&self) -> Vec<HeldLock> {
+        let trans = unsafe { &*self.raw };
+        let mut ret = Vec::new();
+
+        for (idx, path) in trans.paths.iter().enumerate() {
+            if trans.paths_allocated & (1u64 << idx) == 0 {
+                continue;
+            }
+
+            for level in 0..path.l.len() as u8 {
+                let lock_type = match (path.nodes_locked >> (level << 1)) & 3 {
+                    1 => LockType::Read,
+                    2 => LockType::Intent,
+                    3 => LockType::Write,
+                    _ => continue,
+                };
+
+                ret.push(HeldLock {
+                    btree:  path.btree_id(),
+                    level,
+                    lock_type,
+                    cached: path.cached(),
+                    pos:    path.pos,
+                });
+            }
+        }
+
+        ret
+    }
+
+    /// As for_each_key(), but walking from `end` down to `start`: visits the
+    /// same keys for the same range, in reverse order
+    pub fn for_each_key_reverse<F>(&self,
+        btree:  c::btree_id,
+        start:  c::bpos,
+        end:    c::bpos,
+        flags:  BtreeIterFlags,
+        mut f:  F) -> Result<(), bch_errcode>
+        where F: FnMut(BkeySC) -> ControlFlow<()> {
+        let mut iter = BtreeIter::new(self, btree, end, flags);
+        let extents = iter.is_extents();
+
+        while let Some(k) = iter.peek_prev_and_restart()? {
+            /*
+             * Extents are visited going forwards if they end after @start,
+             * and not if they straddle @end:
+             */
+            if k.k.p < start || (extents && k.k.p == start) {
+                break;
+            }
+
+            if k.k.p <= end && f(k).is_break() {
+                break;
+            }
+
+            if !iter.rewind() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Look up the key at exactly `pos` (including the snapshot) via the btree
+    /// key cache, filling the cache on a miss; for btrees without a key cache
+    /// this is a normal lookup
+    pub fn lookup_cached(&self, btree: c::btree_id, pos: c::bpos) -> Result<Option<OwnedBkey>, bch_errcode> {
+        let cached = unsafe { (*(*self.raw).c).btree_key_cache_btrees } & (1 << btree as u32) != 0;
+        let flags = if cached { BtreeIterFlags::CACHED } else { BtreeIterFlags::empty() };
+
+        self.lockrestart_do(|| {
+            let mut iter = BtreeIter::new(self, btree, pos, flags);
+            let k = iter.peek_slot()?;
+
+            Ok((k.k.type_ != c::bch_bkey_type::KEY_TYPE_deleted as u8).then(|| k.to_owned_bkey()))
+        })
+    }
+}
+
+impl Fs {
+    /// Walk `btree` from `end` down to `start`, as with
+    /// BtreeTrans::for_each_key_reverse()
+    pub fn scan_reverse<F>(&self,
+        btree:  c::btree_id,
+        start:  c::bpos,
+        end:    c::bpos,
+        flags:  BtreeIterFlags,
+        f:      F) -> Result<(), bch_errcode>
+        where F: FnMut(BkeySC) -> ControlFlow<()> {
+        BtreeTrans::new(self).for_each_key_reverse(btree, start, end, flags, f)
+    }
+
+    /// Read the leaf nodes covering up to `max_nodes` positions spread evenly
+    /// between the first and last key of `btree`, for estimating statistics
+    /// without scanning the whole tree
+    pub fn sample_nodes(&self, btree: c::btree_id, max_nodes: usize) -> Result<Vec<NodeStats>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut ret: Vec<NodeStats> = Vec::new();
+
+        if max_nodes == 0 {
+            return Ok(ret);
+        }
+
+        let (first, last) = {
+            let mut iter = BtreeIter::new(&trans, btree, POS_MIN, BtreeIterFlags::ALL_SNAPSHOTS);
+            let first = match iter.peek_and_restart()? {
+                Some(k) => k.k.p,
+                None    => return Ok(ret),
+            };
+            drop(iter);
+
+            let mut iter = BtreeIter::new(&trans, btree, SPOS_MAX, BtreeIterFlags::ALL_SNAPSHOTS);
+            let last = iter.peek_prev_and_restart()?.map_or(first, |k| k.k.p);
+            (first, last)
+        };
+
+        for i in 0..max_nodes as u64 {
+            let n = max_nodes as u64;
+            let target = if first.inode != last.inode {
+                pos(first.inode + ((last.inode - first.inode) as u128 * i as u128 / n as u128) as u64, 0)
+            } else {
+                pos(first.inode, first.offset + ((last.offset - first.offset) as u128 * i as u128 / n as u128) as u64)
+            };
+
+            if let Some(prev) = ret.last() {
+                if target <= prev.max_key {
+                    continue;
+                }
+            }
+
+            let mut iter = BtreeNodeIter::new(&trans, btree, target, 0, 0, BtreeIterFlags::PREFETCH);
+            if let Some(b) = iter.peek_and_restart()? {
+                ret.push(b.stats(self));
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Walk every key in `btree`, in all snapshots, calling `f` once per
+    /// inode number with that inode's keys
+    pub fn group_by_inode<F>(&self, btree: c::btree_id, mut f: F) -> Result<(), bch_errcode>
+        where F: FnMut(u64, &[OwnedBkey]) {
+        let trans = BtreeTrans::new(self);
+        let mut group: Vec<OwnedBkey> = Vec::new();
+
+        trans.for_each_key(btree, POS_MIN, SPOS_MAX, BtreeIterFlags::ALL_SNAPSHOTS, |k| {
+            if let Some(prev) = group.last() {
+                if prev.k().p.inode != k.k.p.inode {
+                    f(prev.k().p.inode, &group);
+                    group.clear();
+                }
+            }
+
+            group.push(k.to_owned_bkey());
+            ControlFlow::Continue(())
+        })?;
+
+        if let Some(last) = group.last() {
+            f(last.k().p.inode, &group);
+        }
+
+        Ok(())
+    }
+
+    /// Walk every key of type `ty`, in all snapshots, in each btree that can
+    /// hold keys of that type
+    pub fn scan_type<F>(&self, ty: c::bch_bkey_type, mut f: F) -> Result<(), bch_errcode>
+        where F: FnMut(c::btree_id, BkeySC) {
+        let trans = BtreeTrans::new(self);
+
+        for id in 0..c::btree_id::BTREE_ID_NR as u32 {
+            let btree: c::btree_id = unsafe { std::mem::transmute(id) };
+
+            if !unsafe { c::bch2_btree_type_has_key_type(btree, ty) } {
+                continue;
+            }
+
+            trans.for_each_key(btree, POS_MIN, SPOS_MAX, BtreeIterFlags::ALL_SNAPSHOTS, |k| {
+                if k.k.type_ == ty as u8 {
+                    f(btree, k);
+                }
+                ControlFlow::Continue(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn btree_depth(&self, btree: c::btree_id) -> u8 {
+        unsafe { (*self.raw).btree_roots_known[btree as usize].level + 1 }
+    }
+
+    /// Depth and leaf node fill of `btree`; None if it's empty
+    pub fn btree_health(&self, btree: c::btree_id) -> Result<Option<BtreeHealth>, bch_errcode> {
+        let nodes = self.sample_nodes(btree, HEALTH_SAMPLE_NODES)?;
+
+        if nodes.is_empty() {
+            return Ok(None);
+        }
+
+        let fill = nodes.iter().map(|n| n.fill);
+
+        Ok(Some(BtreeHealth {
+            depth:      self.btree_depth(btree),
+            min_fill:   fill.clone().fold(f64::INFINITY, f64::min),
+            max_fill:   fill.clone().fold(0.0, f64::max),
+            avg_fill:   fill.sum::<f64>() / nodes.len() as f64,
+        }))
+    }
+
+    /// Rewrite the leaf node of `btree` containing `pos`: the new node is
+    /// compacted, and merged with its neighbours if they're small enough
+    pub fn rewrite_btree_node(&self, btree: c::btree_id, pos: c::bpos) -> Result<(), bch_errcode> {
+        let trans = BtreeTrans::new(self);
+
+        trans.lockrestart_do(|| {
+            let mut iter = BtreeNodeIter::new(&trans, btree, pos, 0, 0, BtreeIterFlags::empty());
+            let b = match iter.peek()? {
+                Some(b) => b as *const c::btree as *mut c::btree,
+                None    => return Ok(()),
+            };
+
+            errcode_to_result(unsafe { c::bch2_btree_node_rewrite(trans.raw, &mut iter.raw, b, 0) })
+                .map(drop)
+        })
+    }
+
+    /// Number of keys currently in the btree key cache
+    pub fn key_cache_nr_keys(&self) -> u64 {
+        unsafe { (*self.raw).btree_key_cache.nr_keys.counter as u64 }
+    }
+
+    /// A snapshot of btree node cache usage; the cache is changing underneath,
+    /// so the fields may not quite agree with each other
+    pub fn btree_cache_stats(&self) -> BtreeCacheStats {
+        let bc = unsafe { &(*self.raw).btree_cache };
+        let node_bytes = unsafe { (*self.raw).opts.btree_node_size } as u64;
+
+        BtreeCacheStats {
+            size:       unsafe { std::ptr::read_volatile(&bc.shrink.limit) } as u64 * node_bytes,
+            used:       unsafe { std::ptr::read_volatile(&bc.used) } as u64 * node_bytes,
+            dirty:      unsafe { std::ptr::read_volatile(&bc.dirty.counter) } as u64 * node_bytes,
+        }
+    }
+
+    /// Everything currently in the btree node cache, for debugging
+    pub fn btree_cache_contents(&self) -> Vec<CachedNode> {
+        let mut nodes: Vec<CachedNode> = Vec::new();
+
+        unsafe {
+            c::bch2_btree_cache_for_each(self.raw, Some(btree_cache_push),
+                &mut nodes as *mut Vec<CachedNode> as *mut c_void)
+        };
+        nodes
+    }
+
+    /// Rough estimate of how long a full fsck would take: the time to read
+    /// every btree node once, going by each device's average read latency so
+    /// far. fsck does more than that - walking keys, and rereading nodes that
+    /// fall out of the cache - so this is a lower bound more than anything.
+    pub fn estimate_fsck_time(&self) -> Result<Duration, bch_errcode> {
+        let node_sectors = unsafe { (*self.raw).opts.btree_node_size } as u64 >> 9;
+        let mut ns = 0;
+
+        for dev in self.devs() {
+            let btree_sectors = self.dev_usage(dev)?.d[c::bch_data_type::BCH_DATA_btree as usize].sectors;
+            let stats = unsafe { (*(*self.raw).devs[dev as usize]).io_latency[0].duration_stats };
+            let (n, sum) = (stats.n, stats.sum);
+            let node_read_ns = if n > 0 && sum > 0 {
+                sum as u64 / n as u64
+            } else {
+                FSCK_DEFAULT_NODE_READ_NS
+            };
+
+            ns += btree_sectors / node_sectors * node_read_ns;
+        }
+
+        Ok(Duration::from_nanos(ns))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockType {
+    Read,
+    Intent,
+    Write,
+}
+
+/// A node lock held by one of a transaction's btree paths
+#[derive(Clone, Copy, Debug)]
+pub struct HeldLock {
+    pub btree:      c::btree_id,
+    pub level:      u8,
+    pub lock_type:  LockType,
+    /// Lock is on a key cache entry, not a btree node
+    pub cached:     bool,
+    pub pos:        c::bpos,
 }
 
 impl<'f> Drop for BtreeTrans<'f> {
@@ -50,6 +398,24 @@ bitflags! {
     }
 }
 
+impl BtreeIterFlags {
+    /// The flags for a plain read of the current version of `btree`: extents
+    /// btrees are iterated as extents, and snapshots are filtered in btrees
+    /// that have them - so the start position must have the snapshot ID to
+    /// read from
+    pub fn for_read(btree: c::btree_id) -> BtreeIterFlags {
+        let mut flags = BtreeIterFlags::empty();
+
+        if unsafe { c::bch2_btree_id_is_extents(btree) } {
+            flags |= BtreeIterFlags::IS_EXTENTS;
+        }
+        if unsafe { c::bch2_btree_type_has_snapshots(btree) } {
+            flags |= BtreeIterFlags::FILTER_SNAPSHOTS;
+        }
+        flags
+    }
+}
+
 pub struct BtreeIter<'t> {
     raw:    c::btree_iter,
     trans:  PhantomData<&'t BtreeTrans<'t>>,
@@ -92,11 +458,47 @@ impl<'t> BtreeIter<'t> {
         }
     }
 
+    /// Returns the key at exactly the iterator's position - a deleted key if
+    /// there isn't one
+    pub fn peek_slot(&mut self) -> Result<BkeySC, bch_errcode> {
+        unsafe {
+            let k = c::bch2_btree_iter_peek_slot(&mut self.raw);
+            errptr_to_result_c(k.k).map(|_| BkeySC { k: &*k.k, v: &*k.v, iter: PhantomData })
+        }
+    }
+
+    pub fn peek_prev_and_restart(&mut self) -> Result<Option<BkeySC>, bch_errcode> {
+        unsafe {
+            loop {
+                let k = c::bch2_btree_iter_peek_prev(&mut self.raw);
+
+                match errptr_to_result_c(k.k) {
+                    Err(e) if e.is_restart() => {
+                        c::bch2_trans_begin(self.raw.trans);
+                    }
+                    r => return r.map(|_| if !k.k.is_null() { Some(BkeySC{ k: &*k.k, v: &*k.v, iter: PhantomData }) } else { None } ),
+                }
+            }
+        }
+    }
+
     pub fn advance(&mut self) {
         unsafe {
             c::bch2_btree_iter_advance(&mut self.raw);
         }
     }
+
+    /// Move to just before the start of the last key returned; false if we
+    /// were already at the start of the btree
+    pub fn rewind(&mut self) -> bool {
+        unsafe { c::bch2_btree_iter_rewind(&mut self.raw) }
+    }
+
+    /// Whether this iterator has extent semantics - keys cover a range,
+    /// ending at their position
+    pub fn is_extents(&self) -> bool {
+        self.raw.flags & c::BTREE_ITER_IS_EXTENTS as u16 != 0
+    }
 }
 
 impl<'t> Drop for BtreeIter<'t> {
@@ -166,7 +568,36 @@ impl<'t> Drop for BtreeNodeIter<'t> {
     }             
 }
 
+/// Fill statistics of a single btree node
+#[derive(Clone, Copy, Debug)]
+pub struct NodeStats {
+    pub btree:      c::btree_id,
+    pub level:      u8,
+    pub min_key:    c::bpos,
+    pub max_key:    c::bpos,
+    pub nr_keys:    u32,
+    pub live_u64s:  u32,
+    /// Fraction of the node's space used by live keys
+    pub fill:       f64,
+}
+
 impl<'b, 'f> c::btree {
+    pub fn stats(&'b self, fs: &'f Fs) -> NodeStats {
+        let node_bytes = unsafe { (*fs.raw).opts.btree_node_size } as usize;
+        let max_u64s = (node_bytes - std::mem::size_of::<c::btree_node>()) / std::mem::size_of::<u64>();
+        let live_u64s = self.nr.live_u64s as u32;
+
+        NodeStats {
+            btree:      unsafe { std::mem::transmute(self.c.btree_id as u32) },
+            level:      self.c.level,
+            min_key:    unsafe { (*self.data).min_key },
+            max_key:    self.key.k.p,
+            nr_keys:    self.nr.packed_keys as u32 + self.nr.unpacked_keys as u32,
+            live_u64s,
+            fill:       live_u64s as f64 / max_u64s as f64,
+        }
+    }
+
     pub fn to_text(&'b self, fs: &'f Fs) -> BtreeNodeToText<'b, 'f> {
         BtreeNodeToText { b: &self, fs }
     }
@@ -197,3 +628,51 @@ impl<'b, 'f> fmt::Display for BtreeNodeOndiskToText<'b, 'f> {
         printbuf_to_formatter(f, |buf| unsafe { c::bch2_btree_node_ondisk_to_text(buf, self.fs.raw, self.b) })
     }
 }
+
+/// How well filled a btree's leaf nodes are, from a sample of them
+#[derive(Clone, Copy, Debug)]
+pub struct BtreeHealth {
+    /// Number of levels, including the leaves
+    pub depth:      u8,
+    pub min_fill:   f64,
+    pub max_fill:   f64,
+    pub avg_fill:   f64,
+}
+
+const HEALTH_SAMPLE_NODES: usize = 64;
+
+/// A node in the btree node cache
+#[derive(Clone, Copy, Debug)]
+pub struct CachedNode {
+    pub btree:      c::btree_id,
+    pub level:      u8,
+    /// The node's max key
+    pub pos:        c::bpos,
+    /// Has changes not yet written out
+    pub dirty:      bool,
+}
+
+unsafe extern "C" fn btree_cache_push(p: *mut c_void, b: *mut c::btree, dirty: bool) {
+    let nodes = &mut *(p as *mut Vec<CachedNode>);
+
+    nodes.push(CachedNode {
+        btree:      std::mem::transmute((*b).c.btree_id as u32),
+        level:      (*b).c.level,
+        pos:        (*b).key.k.p,
+        dirty,
+    });
+}
+
+/// Btree node cache usage, in bytes
+#[derive(Clone, Copy, Debug)]
+pub struct BtreeCacheStats {
+    /// The bound set when the filesystem was opened, rounded down to whole
+    /// nodes; 0 if there's none
+    pub size:       u64,
+    /// Everything in the cache, including the reserve
+    pub used:       u64,
+    pub dirty:      u64,
+}
+
+/* Assumed time to read a btree node, for devices we haven't timed any reads on: */
+const FSCK_DEFAULT_NODE_READ_NS: u64 = 1_000_000;
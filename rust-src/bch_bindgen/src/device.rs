@@ -0,0 +1,375 @@
+use crate::c;
+use crate::bkey::{BkeySC, BkeyValC};
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::{bch_errcode, errcode_to_result};
+use crate::fs::Fs;
+use crate::pos;
+use crate::sb::SuperInfo;
+use memoffset::offset_of;
+use std::ffi::CString;
+use std::ops::ControlFlow;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+pub use crate::c::bch_member_state as DeviceState;
+
+const BCH_DATA_NR: usize = c::bch_data_type::BCH_DATA_NR as usize;
+
+/* from bcachefs_format.h: */
+const BCH_SB_SECTOR: u64 = 8;
+const BCACHE_MAGIC: uuid::Uuid = uuid::Uuid::from_u128(0xc68573f6_4e1a_45ca_8265_f57f48ba6d81);
+const BCHFS_MAGIC: uuid::Uuid = uuid::Uuid::from_u128(0xc68573f6_66ce_90a9_d96a_60cf803df7ef);
+
+/* from disk_groups.h: */
+const TARGET_DEV_START: u32 = 1;
+
+impl Fs {
+    pub fn dev_exists(&self, dev_idx: u32) -> bool {
+        unsafe {
+            dev_idx < (*self.raw).sb.nr_devices as u32 &&
+                !(*self.raw).devs[dev_idx as usize].is_null()
+        }
+    }
+
+    /// Indices of the devices currently in the filesystem
+    pub fn devs(&self) -> impl Iterator<Item = u32> + '_ {
+        let nr = unsafe { (*self.raw).sb.nr_devices } as u32;
+
+        (0..nr).filter(move |&i| self.dev_exists(i))
+    }
+
+    fn dev_check(&self, dev_idx: u32) -> Result<(), bch_errcode> {
+        if self.dev_exists(dev_idx) {
+            Ok(())
+        } else {
+            Err(bch_errcode::BCH_ERR_ENOENT_dev_idx_not_found)
+        }
+    }
+
+    /// Per-device usage by data type, from the in-memory accounting
+    pub fn dev_usage(&self, dev_idx: u32) -> Result<c::bch_dev_usage, bch_errcode> {
+        self.dev_check(dev_idx)?;
+
+        let mut usage: c::bch_dev_usage = Default::default();
+        unsafe { c::bch2_dev_usage_read_fast((*self.raw).devs[dev_idx as usize], &mut usage) };
+        Ok(usage)
+    }
+
+    /// Per-device usage by data type, summed from the alloc btree - i.e. what's
+    /// on disk, which the in-memory counters in [`Fs::dev_usage`] should agree
+    /// with. Arrays are indexed by `bch_data_type`; cached sectors are counted
+    /// under BCH_DATA_cached whatever the bucket's data type, as in
+    /// bch2_dev_usage_update()
+    pub fn device_accounting(&self, dev_idx: u32) -> Result<DeviceAccounting, bch_errcode> {
+        self.dev_check(dev_idx)?;
+
+        let bucket_size = unsafe { (*(*self.raw).devs[dev_idx as usize]).mi.bucket_size } as u64;
+        let trans = BtreeTrans::new(self);
+        let mut acct = DeviceAccounting::default();
+
+        trans.for_each_key(c::btree_id::BTREE_ID_alloc,
+            pos(dev_idx as u64, 0), pos(dev_idx as u64, u64::MAX),
+            BtreeIterFlags::PREFETCH,
+            |k| {
+                let mut a: c::bch_alloc_v4 = Default::default();
+                unsafe { c::__bch2_alloc_to_v4(k.to_raw(), &mut a) };
+
+                let t = a.data_type as usize;
+                let dirty = a.dirty_sectors as u64;
+
+                acct.buckets[t]     += 1;
+                acct.sectors[t]     += dirty;
+                acct.sectors[c::bch_data_type::BCH_DATA_cached as usize] += a.cached_sectors as u64;
+                if dirty != 0 {
+                    acct.fragmented[t] += bucket_size.saturating_sub(dirty);
+                }
+                ControlFlow::Continue(())
+            })?;
+
+        Ok(acct)
+    }
+
+    /// Whether device `dev_idx` holds any user data (not counting cached
+    /// data), answered from accounting rather than a scan
+    pub fn device_has_user_data(&self, dev_idx: u32) -> Result<bool, bch_errcode> {
+        use c::bch_data_type::*;
+
+        let usage = self.dev_usage(dev_idx)?;
+
+        Ok([BCH_DATA_user, BCH_DATA_parity, BCH_DATA_stripe].iter()
+            .any(|&t| usage.d[t as usize].sectors != 0))
+    }
+
+    /// Change device `dev_idx`'s state - rw, ro, failed or spare - and write
+    /// the superblock, as `bcachefs device set-state` does. Not forced: fails
+    /// with BCH_ERR_device_state_not_allowed if that would leave data degraded
+    /// or missing.
+    pub fn set_device_state(&self, dev_idx: u32, state: DeviceState) -> Result<(), bch_errcode> {
+        self.dev_check(dev_idx)?;
+
+        errcode_to_result(unsafe {
+            c::bch2_dev_set_state(self.raw, (*self.raw).devs[dev_idx as usize], state, 0)
+        })?;
+        Ok(())
+    }
+
+    pub fn device_state(&self, dev_idx: u32) -> Result<DeviceState, bch_errcode> {
+        self.dev_check(dev_idx)?;
+
+        Ok(unsafe { std::mem::transmute((*(*self.raw).devs[dev_idx as usize]).mi.state as u32) })
+    }
+
+    /// When each device last completed a journal flush write (FUA), for
+    /// devices that have had one since the filesystem was opened. Journal
+    /// flushes are the only FUA writes, so this is tracked by the block
+    /// device.
+    pub fn journal_device_flush_times(&self) -> Vec<(u32, SystemTime)> {
+        self.devs()
+            .filter_map(|dev| {
+                let ns = unsafe {
+                    let bdev = (*(*self.raw).devs[dev as usize]).disk_sb.bdev;
+
+                    if bdev.is_null() {
+                        return None;
+                    }
+                    std::ptr::read_volatile(&(*bdev).bd_last_flush_time)
+                };
+
+                (ns != 0).then(|| (dev, SystemTime::UNIX_EPOCH + Duration::from_nanos(ns)))
+            })
+            .collect()
+    }
+
+    /// As write_file(), but the data is allocated only on device `dev_idx`,
+    /// for tests that depend on data placement; fails if that device is full
+    /// rather than falling back to others.
+    pub fn write_file_on_device(&self, dev_idx: u32, subvol: u32, inum: u64, offset: u64,
+                                data: &[u8]) -> Result<(), bch_errcode> {
+        self.dev_check(dev_idx)?;
+        self.write_file_to_target(subvol, inum, offset, data, TARGET_DEV_START + dev_idx)
+    }
+
+    /// Returns the start (inode, offset in sectors) of every extent with data
+    /// on device `dev_idx`, found via the backpointers btree instead of walking
+    /// every extent in the filesystem. Reflinked data is included: those
+    /// extents live in the reflink btree, where keys are all in inode 0, and
+    /// the offset is into the reflink btree rather than any one file.
+    pub fn extents_on_device(&self, dev_idx: u32) -> Result<impl Iterator<Item = (u64, u64)>, bch_errcode> {
+        self.dev_check(dev_idx)?;
+
+        let trans = BtreeTrans::new(self);
+
+        /* Backpointers may still be sitting in the write buffer: */
+        errcode_to_result(unsafe { c::bch2_btree_write_buffer_flush_sync(trans.raw) })?;
+
+        let mut bps = Vec::new();
+        {
+            let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_backpointers,
+                pos(dev_idx as u64, 0),
+                BtreeIterFlags::PREFETCH);
+            let end = pos(dev_idx as u64, u64::MAX);
+
+            while let Some(k) = iter.peek_and_restart()? {
+                if k.k.p > end {
+                    break;
+                }
+
+                if let BkeyValC::backpointer(bp) = k.v() {
+                    if (bp.btree_id == c::btree_id::BTREE_ID_extents as u8 ||
+                        bp.btree_id == c::btree_id::BTREE_ID_reflink as u8) && bp.level == 0 {
+                        bps.push((bp.btree_id, bp.pos));
+                    }
+                }
+                iter.advance();
+            }
+        }
+
+        /*
+         * A backpointer has the extent's position, i.e. its end - look up the
+         * extent for its size, as bch2_backpointer_get_key() does:
+         */
+        let mut ret = Vec::new();
+        for (btree, p) in bps {
+            let btree = if btree == c::btree_id::BTREE_ID_reflink as u8 {
+                c::btree_id::BTREE_ID_reflink
+            } else {
+                c::btree_id::BTREE_ID_extents
+            };
+
+            let size = trans.lockrestart_do(|| {
+                let mut iter = BtreeIter::new(&trans, btree, p,
+                    BtreeIterFlags::NOT_EXTENTS|BtreeIterFlags::ALL_SNAPSHOTS);
+                let k = iter.peek_slot()?;
+
+                Ok((k.k.type_ != c::bch_bkey_type::KEY_TYPE_deleted as u8).then(|| k.k.size as u64))
+            })?;
+
+            if let Some(size) = size {
+                ret.push((p.inode, p.offset - size));
+            }
+        }
+
+        Ok(ret.into_iter())
+    }
+
+    /// Discard the free buckets on device `dev_idx`, or on every device if
+    /// None - the equivalent of fstrim - returning the number of sectors
+    /// discarded. Devices without the discard option set are skipped, as are
+    /// buckets about to be written to; the filesystem must be read-write.
+    pub fn discard_free_space(&self, dev_idx: Option<u32>) -> Result<u64, bch_errcode> {
+        let devs: Vec<u32> = match dev_idx {
+            Some(dev) => {
+                self.dev_check(dev)?;
+                vec![dev]
+            }
+            None => self.devs().collect(),
+        };
+        let mut sectors = 0;
+
+        for dev in devs {
+            errcode_to_result(unsafe {
+                c::bch2_dev_discard_free_space(self.raw, (*self.raw).devs[dev as usize], &mut sectors)
+            })?;
+        }
+
+        Ok(sectors)
+    }
+}
+
+/// Usage of one device by data type, see [`Fs::device_accounting`]
+#[derive(Clone, Debug, Default)]
+pub struct DeviceAccounting {
+    pub buckets:        [u64; BCH_DATA_NR],
+    pub sectors:        [u64; BCH_DATA_NR],
+    /// Unused space in partially filled buckets
+    pub fragmented:     [u64; BCH_DATA_NR],
+}
+
+/// A decoded alloc key: the state of one bucket
+#[derive(Clone, Copy, Debug)]
+pub struct AllocInfo {
+    pub dev:            u32,
+    pub bucket:         u64,
+    pub gen:            u8,
+    /// A bch_data_type
+    pub data_type:      u8,
+    pub dirty_sectors:  u32,
+    pub cached_sectors: u32,
+    /// Last read and write, in io clock units (sectors of IO done)
+    pub io_time:        [u64; 2],
+}
+
+impl<'a> BkeySC<'a> {
+    /// Decode any version of alloc key
+    pub fn as_alloc(&self) -> Result<AllocInfo, bch_errcode> {
+        use c::bch_bkey_type::*;
+
+        /* Not matched on self.v(), which decodes alloc_v3 keys as inode_v3: */
+        let ty: c::bch_bkey_type = unsafe { std::mem::transmute(self.k.type_ as u32) };
+        if !matches!(ty, KEY_TYPE_alloc | KEY_TYPE_alloc_v2 | KEY_TYPE_alloc_v3 | KEY_TYPE_alloc_v4) {
+            return Err(bch_errcode::BCH_ERR_ENOENT_bkey_type_mismatch);
+        }
+
+        let mut a: c::bch_alloc_v4 = Default::default();
+        unsafe { c::__bch2_alloc_to_v4(self.to_raw(), &mut a) };
+
+        Ok(AllocInfo {
+            dev:            self.k.p.inode as u32,
+            bucket:         self.k.p.offset,
+            gen:            a.gen,
+            data_type:      a.data_type,
+            dirty_sectors:  a.dirty_sectors,
+            cached_sectors: a.cached_sectors,
+            io_time:        a.io_time,
+        })
+    }
+}
+
+impl AllocInfo {
+    /// Approximately how long ago this bucket was last read: the io clock
+    /// counts sectors read, which we convert at the rate they've been read
+    /// since the filesystem was opened. Duration::MAX if nothing has been
+    /// read since then, since the rate is unknown.
+    pub fn read_age(&self, fs: &Fs) -> Duration {
+        let now = unsafe { (*fs.raw).io_clock[0].now.counter } as u64;
+        let (opened_at, clock_at_open) = fs.opened();
+        let sectors_since_open = now.saturating_sub(clock_at_open);
+
+        if sectors_since_open == 0 {
+            return Duration::MAX;
+        }
+
+        let age = now.saturating_sub(self.io_time[0]) as f64;
+        let secs = opened_at.elapsed().as_secs_f64() * age / sectors_since_open as f64;
+
+        if secs >= u64::MAX as f64 {
+            Duration::MAX
+        } else {
+            Duration::from_secs_f64(secs)
+        }
+    }
+}
+
+/// A block device or image that may or may not belong to an open filesystem
+pub struct Device;
+
+/// What a device's superblock says about it and the filesystem it's in
+#[derive(Clone, Debug)]
+pub struct DeviceSuperInfo {
+    /// The filesystem's uuid, the same on every member device
+    pub uuid:           uuid::Uuid,
+    pub internal_uuid:  uuid::Uuid,
+    pub label:          Option<String>,
+    /// This device's index in the filesystem
+    pub dev_idx:        u8,
+    pub nr_devices:     u8,
+    pub seq:            u64,
+    pub version:        u16,
+}
+
+impl Device {
+    /// Whether the device or image at `path` has a bcachefs superblock: only
+    /// the magic number is checked, the superblock isn't validated
+    pub fn is_bcachefs(path: &Path) -> bool {
+        let mut magic = [0u8; 16];
+        let offset = (BCH_SB_SECTOR << 9) + offset_of!(c::bch_sb, magic) as u64;
+
+        std::fs::File::open(path)
+            .and_then(|f| f.read_exact_at(&mut magic, offset))
+            .map_or(false, |_| {
+                let magic = uuid::Uuid::from_bytes(magic);
+                magic == BCACHE_MAGIC || magic == BCHFS_MAGIC
+            })
+    }
+
+    /// Read the superblock of the device at `path`, without opening the
+    /// filesystem; the device is opened read-only and not exclusively
+    pub fn read_super(path: &Path) -> Result<DeviceSuperInfo, bch_errcode> {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let mut opts: c::bch_opts = Default::default();
+        let mut sb: c::bch_sb_handle = Default::default();
+
+        opts.noexcl = 1;
+        opts.set_noexcl_defined(1);
+        opts.nochanges = 1;
+        opts.set_nochanges_defined(1);
+
+        errcode_to_result(unsafe { c::bch2_read_super(path.as_ptr(), &mut opts, &mut sb) })?;
+
+        let s = sb.sb();
+        let info = DeviceSuperInfo {
+            uuid:           s.uuid(),
+            internal_uuid:  uuid::Uuid::from_bytes(s.uuid.b),
+            label:          SuperInfo::new(s).label(),
+            dev_idx:        s.dev_idx,
+            nr_devices:     s.nr_devices,
+            seq:            u64::from_le(s.seq),
+            version:        u16::from_le(s.version),
+        };
+
+        unsafe { c::bch2_free_super(&mut sb) };
+        Ok(info)
+    }
+}
@@ -0,0 +1,116 @@
+use crate::c;
+use crate::bkey::{BkeySC, BkeyValC};
+use crate::btree::{BtreeTrans, BtreeIterFlags};
+use crate::errcode::{bch_errcode, errcode_to_result};
+use crate::fs::Fs;
+use crate::inode::BCACHEFS_ROOT_INO;
+use crate::spos;
+use crate::subvolume::BCACHEFS_ROOT_SUBVOL;
+use byteorder::{LittleEndian, ByteOrder};
+use std::ops::ControlFlow;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+
+/* Not exported by bindgen: */
+pub const DT_SUBVOL: u8 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirentTarget {
+    Inum(u64),
+    Subvol { child: u32, parent: u32 },
+}
+
+/// A decoded dirent; the name borrows from the key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dirent<'a> {
+    /// Inode number of the directory this dirent is in
+    pub dir:    u64,
+    pub target: DirentTarget,
+    pub d_type: u8,
+    pub name:   &'a [u8],
+}
+
+impl<'a> BkeySC<'a> {
+    pub fn as_dirent(&self) -> Result<Dirent<'a>, bch_errcode> {
+        if !matches!(self.v(), BkeyValC::dirent(_)) {
+            return Err(bch_errcode::BCH_ERR_ENOENT_bkey_type_mismatch);
+        }
+
+        let b = self.val_bytes();
+        let d_type = b[8];
+        let target = if d_type == DT_SUBVOL {
+            DirentTarget::Subvol {
+                child:  LittleEndian::read_u32(&b[0..]),
+                parent: LittleEndian::read_u32(&b[4..]),
+            }
+        } else {
+            DirentTarget::Inum(LittleEndian::read_u64(b))
+        };
+
+        /* The name is padded out to the end of the value with nuls: */
+        let name = &b[9..];
+        let len = name.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1);
+
+        Ok(Dirent { dir: self.k.p.inode, target, d_type, name: &name[..len] })
+    }
+}
+
+impl Fs {
+    /// Number of entries in directory `dir_inum` in the root subvolume.
+    ///
+    /// Directories don't keep a count of their entries, so this walks the
+    /// directory's hash range; as with readdir, subvolume dirents only count
+    /// in the subvolume they were created in.
+    pub fn dirent_count(&self, dir_inum: u64) -> Result<u64, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let snapshot = trans.lockrestart_do(|| trans.subvol_snapshot(BCACHEFS_ROOT_SUBVOL))?;
+        let mut nr = 0;
+
+        trans.for_each_key(c::btree_id::BTREE_ID_dirents,
+            spos(dir_inum, 0, snapshot),
+            spos(dir_inum, u64::MAX, u32::MAX),
+            BtreeIterFlags::FILTER_SNAPSHOTS,
+            |k| {
+                match k.as_dirent() {
+                    Ok(Dirent { target: DirentTarget::Subvol { parent, .. }, .. })
+                        if parent != BCACHEFS_ROOT_SUBVOL => {},
+                    Ok(_)   => nr += 1,
+                    Err(_)  => {},
+                }
+                ControlFlow::Continue(())
+            })?;
+
+        Ok(nr)
+    }
+
+    /// Resolve `path`, relative to the root of the filesystem, to the inode it
+    /// refers to; subvolumes are followed, symlinks aren't. `..` isn't
+    /// supported, paths must be normalized - EINVAL otherwise.
+    pub fn lookup_path(&self, path: &Path) -> Result<c::subvol_inum, bch_errcode> {
+        let mut inum = c::subvol_inum { subvol: BCACHEFS_ROOT_SUBVOL, inum: BCACHEFS_ROOT_INO };
+
+        for component in path.components() {
+            let name = match component {
+                Component::RootDir | Component::CurDir  => continue,
+                Component::Normal(name)                 => name.as_bytes(),
+                _ => return Err(bch_errcode::from_raw(libc::EINVAL)),
+            };
+            let dir = inum;
+
+            errcode_to_result(unsafe {
+                c::bch2_file_lookup(self.raw, dir, name.as_ptr(), name.len() as u32, &mut inum)
+            })?;
+        }
+
+        Ok(inum)
+    }
+
+    /// Whether two paths refer to the same inode - i.e. are hardlinks of each
+    /// other
+    pub fn same_inode(&self, path_a: &Path, path_b: &Path) -> Result<bool, bch_errcode> {
+        let a = self.lookup_path(path_a)?;
+        let b = self.lookup_path(path_b)?;
+
+        Ok(a.subvol == b.subvol && a.inum == b.inum)
+    }
+}
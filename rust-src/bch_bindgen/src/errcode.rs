@@ -6,11 +6,35 @@ pub use crate::c::bch_errcode;
 
 impl fmt::Display for bch_errcode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let s = unsafe { CStr::from_ptr(bcachefs::bch2_err_str(*self as i32)) };
+        let s = unsafe { CStr::from_ptr(bcachefs::bch2_err_str(self.0 as i32)) };
         write!(f, "{:?}", s)
     }
 }
 
+impl bch_errcode {
+    /// From a positive error code: a private BCH_ERR_* code, or a standard
+    /// errno - bch_errcode is a newtype rather than a Rust enum so that those
+    /// are valid too
+    pub fn from_raw(err: i32) -> bch_errcode {
+        bch_errcode(err as u32)
+    }
+
+    /// Whether this error is `class` or one of its subclasses
+    pub fn matches(self, class: bch_errcode) -> bool {
+        unsafe { bcachefs::__bch2_err_matches(-(self.0 as i32), -(class.0 as i32)) }
+    }
+
+    /// Whether this error is the standard error `errno`, or a private error
+    /// code in that class
+    pub fn matches_errno(self, errno: i32) -> bool {
+        unsafe { bcachefs::__bch2_err_matches(-(self.0 as i32), -errno) }
+    }
+
+    pub fn is_restart(self) -> bool {
+        self.matches(bch_errcode::BCH_ERR_transaction_restart)
+    }
+}
+
 /* Can we make a function generic over ptr constness? */
 
 pub fn errptr_to_result<T>(p: *mut T) -> Result<*mut T, bch_errcode> {
@@ -18,8 +42,7 @@ pub fn errptr_to_result<T>(p: *mut T) -> Result<*mut T, bch_errcode> {
     let max_err: isize = -4096;
     if addr > max_err as usize {
         let addr = addr as i32;
-        let err: bch_errcode = unsafe { std::mem::transmute(-addr) };
-        Err(err)
+        Err(bch_errcode::from_raw(-addr))
     } else {
         Ok(p)
     }
@@ -30,11 +53,48 @@ pub fn errptr_to_result_c<T>(p: *const T) -> Result<*const T, bch_errcode> {
     let max_err: isize = -4096;
     if addr > max_err as usize {
         let addr = addr as i32;
-        let err: bch_errcode = unsafe { std::mem::transmute(-addr) };
-        Err(err)
+        Err(bch_errcode::from_raw(-addr))
     } else {
         Ok(p)
     }
 }
 
+pub fn errcode_to_result(ret: i32) -> Result<i32, bch_errcode> {
+    if ret < 0 {
+        Err(bch_errcode::from_raw(-ret))
+    } else {
+        Ok(ret)
+    }
+}
+
 impl std::error::Error for bch_errcode {}
+
+/// The errno an io::Error carries, or EIO if it doesn't have one
+pub(crate) fn io_err_to_errcode(e: std::io::Error) -> bch_errcode {
+    bch_errcode::from_raw(e.raw_os_error().unwrap_or(libc::EIO))
+}
+
+/// Errors from operations that can also fail because something isn't
+/// available in this build of libbcachefs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BchError {
+    Errcode(bch_errcode),
+    Unsupported { feature: String },
+}
+
+impl From<bch_errcode> for BchError {
+    fn from(err: bch_errcode) -> Self {
+        BchError::Errcode(err)
+    }
+}
+
+impl fmt::Display for BchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BchError::Errcode(err)              => write!(f, "{}", err),
+            BchError::Unsupported { feature }   => write!(f, "{} not supported by this build", feature),
+        }
+    }
+}
+
+impl std::error::Error for BchError {}
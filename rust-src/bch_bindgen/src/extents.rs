@@ -0,0 +1,459 @@
+use crate::c;
+use crate::bkey::{BkeySC, BkeyValC};
+use crate::btree::{BtreeTrans, BtreeIterFlags};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::{spos, POS_MIN, SPOS_MAX};
+use crate::subvolume::BCACHEFS_ROOT_SUBVOL;
+use byteorder::{LittleEndian, ByteOrder};
+use std::ops::ControlFlow;
+
+/*
+ * Decoding of extent entries (pointers, checksum/compression info, stripe
+ * pointers): the C helpers for walking these are all inline, and
+ * bch_extent_ptr/bch_extent_crc* are opaque bitfields on the Rust side, so we
+ * decode the little endian on disk layout here.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExtentPtr {
+    pub dev:        u8,
+    /// In sectors
+    pub offset:     u64,
+    pub gen:        u8,
+    pub cached:     bool,
+    pub unwritten:  bool,
+}
+
+/// Checksum and compression info; applies to the pointers that follow it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExtentCrc {
+    pub compressed_size:    u32,
+    pub uncompressed_size:  u32,
+    pub offset:             u32,
+    pub nonce:              u32,
+    pub csum_type:          u8,
+    pub compression_type:   u8,
+    pub csum:               c::bch_csum,
+}
+
+impl ExtentCrc {
+    pub fn is_compressed(&self) -> bool {
+        self.compression_type != c::bch_compression_type::BCH_COMPRESSION_TYPE_none as u8 &&
+            self.compression_type != c::bch_compression_type::BCH_COMPRESSION_TYPE_incompressible as u8
+    }
+}
+
+impl PartialEq for c::bch_csum {
+    fn eq(&self, other: &Self) -> bool {
+        let (l_lo, l_hi) = (self.lo, self.hi);
+        let (r_lo, r_hi) = (other.lo, other.hi);
+
+        l_lo == r_lo && l_hi == r_hi
+    }
+}
+
+impl Eq for c::bch_csum {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExtentStripePtr {
+    pub idx:        u64,
+    pub block:      u8,
+    pub redundancy: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtentEntry {
+    Ptr(ExtentPtr),
+    Crc(ExtentCrc),
+    StripePtr(ExtentStripePtr),
+    Rebalance { compression: u8, target: u16 },
+}
+
+/// A pointer along with the crc entry and stripe pointer that apply to it,
+/// as with extent_ptr_decoded in C
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PtrDecoded {
+    pub ptr:    ExtentPtr,
+    pub crc:    Option<ExtentCrc>,
+    pub ec:     Option<ExtentStripePtr>,
+}
+
+impl PtrDecoded {
+    /// Sectors on disk for `sectors` of live data - the compressed size for
+    /// compressed extents, as with ptr_disk_sectors() in C
+    pub fn disk_sectors(&self, sectors: u64) -> u64 {
+        match self.crc {
+            Some(crc) if crc.is_compressed() =>
+                (sectors * crc.compressed_size as u64 + crc.uncompressed_size as u64 - 1) /
+                    crc.uncompressed_size as u64,
+            _ => sectors,
+        }
+    }
+}
+
+fn bits(v: u64, shift: u32, nr: u32) -> u64 {
+    (v >> shift) & ((1u64 << nr) - 1)
+}
+
+fn decode_ptr(v: u64) -> ExtentPtr {
+    ExtentPtr {
+        cached:     bits(v, 1, 1) != 0,
+        unwritten:  bits(v, 3, 1) != 0,
+        offset:     bits(v, 4, 44),
+        dev:        bits(v, 48, 8) as u8,
+        gen:        bits(v, 56, 8) as u8,
+    }
+}
+
+fn decode_entry(b: &[u8]) -> Option<(ExtentEntry, usize)> {
+    if b.len() < 8 {
+        return None;
+    }
+
+    let v = LittleEndian::read_u64(b);
+
+    match v.trailing_zeros() {
+        0 => Some((ExtentEntry::Ptr(decode_ptr(v)), 8)),
+        1 => Some((ExtentEntry::Crc(ExtentCrc {
+            compressed_size:    bits(v, 2, 7) as u32 + 1,
+            uncompressed_size:  bits(v, 9, 7) as u32 + 1,
+            offset:             bits(v, 16, 7) as u32,
+            nonce:              0,
+            csum_type:          bits(v, 24, 4) as u8,
+            compression_type:   bits(v, 28, 4) as u8,
+            csum:               c::bch_csum { lo: bits(v, 32, 32), hi: 0 },
+        }), 8)),
+        2 if b.len() >= 16 => Some((ExtentEntry::Crc(ExtentCrc {
+            compressed_size:    bits(v, 3, 9) as u32 + 1,
+            uncompressed_size:  bits(v, 12, 9) as u32 + 1,
+            offset:             bits(v, 21, 9) as u32,
+            nonce:              bits(v, 30, 10) as u32,
+            csum_type:          bits(v, 40, 4) as u8,
+            compression_type:   bits(v, 44, 4) as u8,
+            csum:               c::bch_csum {
+                lo: LittleEndian::read_u64(&b[8..]),
+                hi: bits(v, 48, 16),
+            },
+        }), 16)),
+        3 if b.len() >= 24 => Some((ExtentEntry::Crc(ExtentCrc {
+            compressed_size:    bits(v, 4, 13) as u32 + 1,
+            uncompressed_size:  bits(v, 17, 13) as u32 + 1,
+            offset:             bits(v, 30, 13) as u32,
+            nonce:              bits(v, 43, 13) as u32,
+            csum_type:          bits(v, 56, 4) as u8,
+            compression_type:   bits(v, 60, 4) as u8,
+            csum:               c::bch_csum {
+                lo: LittleEndian::read_u64(&b[8..]),
+                hi: LittleEndian::read_u64(&b[16..]),
+            },
+        }), 24)),
+        4 => Some((ExtentEntry::StripePtr(ExtentStripePtr {
+            block:      bits(v, 5, 8) as u8,
+            redundancy: bits(v, 13, 4) as u8,
+            idx:        bits(v, 17, 47),
+        }), 8)),
+        5 => Some((ExtentEntry::Rebalance {
+            compression:    bits(v, 40, 8) as u8,
+            target:         bits(v, 48, 16) as u16,
+        }, 8)),
+        _ => None,
+    }
+}
+
+impl<'a> BkeySC<'a> {
+    /// Offset within the value of the extent entries, for key types that
+    /// have them
+    fn extent_entries_start(&self) -> Option<usize> {
+        use c::bch_bkey_type::*;
+
+        let ty: c::bch_bkey_type = unsafe { std::mem::transmute(self.k.type_ as u32) };
+        match ty {
+            KEY_TYPE_extent | KEY_TYPE_btree_ptr   => Some(0),
+            KEY_TYPE_reflink_v                      => Some(8),
+            KEY_TYPE_btree_ptr_v2                   => Some(40),
+            _                                       => None,
+        }
+    }
+
+    /// The extent entries of an extent, reflink_v or btree pointer key;
+    /// empty for other key types
+    pub fn extent_entries(&self) -> Vec<ExtentEntry> {
+        let mut ret = Vec::new();
+        let mut b = match self.extent_entries_start() {
+            Some(start) => &self.val_bytes()[start..],
+            None        => return ret,
+        };
+
+        while let Some((e, size)) = decode_entry(b) {
+            ret.push(e);
+            b = &b[size..];
+        }
+        ret
+    }
+
+    /// Pointers with their crc and stripe info, as with
+    /// bkey_for_each_ptr_decode() in C
+    pub fn ptrs_decoded(&self) -> Vec<PtrDecoded> {
+        let mut ret: Vec<PtrDecoded> = Vec::new();
+        let mut crc = None;
+
+        for e in self.extent_entries() {
+            match e {
+                ExtentEntry::Crc(e_crc)     => crc = Some(e_crc),
+                ExtentEntry::Ptr(ptr)       => ret.push(PtrDecoded { ptr, crc, ec: None }),
+                ExtentEntry::StripePtr(ec)  => if let Some(p) = ret.last_mut() { p.ec = Some(ec) },
+                ExtentEntry::Rebalance { .. } => {},
+            }
+        }
+        ret
+    }
+
+    /// What this key contributes to accounting, following the same rules as
+    /// the extent and reservation triggers
+    pub fn accounting_contribution(&self, fs: &Fs) -> AccountingContribution {
+        use c::bch_data_type::*;
+
+        let mut ret: AccountingContribution = Default::default();
+
+        let sectors = match self.v() {
+            BkeyValC::btree_ptr(_) | BkeyValC::btree_ptr_v2(_) => {
+                ret.data_type = Some(BCH_DATA_btree);
+                unsafe { (*fs.raw).opts.btree_node_size as u64 >> 9 }
+            }
+            BkeyValC::extent(_) | BkeyValC::reflink_v(_) => {
+                ret.data_type = Some(BCH_DATA_user);
+                self.k.size as u64
+            }
+            BkeyValC::reservation(r) => {
+                ret.reserved_sectors = self.k.size as u64 * r.nr_replicas as u64;
+                return ret;
+            }
+            _ => return ret,
+        };
+
+        for p in self.ptrs_decoded() {
+            let disk_sectors = if ret.data_type == Some(BCH_DATA_btree) {
+                sectors
+            } else {
+                p.disk_sectors(sectors)
+            };
+
+            if p.ptr.cached {
+                ret.cached_sectors += disk_sectors;
+            } else {
+                ret.dirty_sectors += disk_sectors;
+                ret.nr_replicas += 1;
+            }
+        }
+
+        ret
+    }
+
+    /// Whether this key points to data, as with bkey_extent_is_data() in C -
+    /// reservations don't
+    pub fn is_extent_data(&self) -> bool {
+        matches!(self.v(),
+            BkeyValC::extent(_) |
+            BkeyValC::btree_ptr(_) |
+            BkeyValC::btree_ptr_v2(_) |
+            BkeyValC::reflink_p(_) |
+            BkeyValC::reflink_v(_) |
+            BkeyValC::inline_data(_) |
+            BkeyValC::indirect_inline_data(_))
+    }
+
+    pub fn as_reflink_p(&self) -> Result<ReflinkPtr, bch_errcode> {
+        match self.v() {
+            BkeyValC::reflink_p(r)  => Ok(ReflinkPtr {
+                idx:        u64::from_le(r.idx),
+                front_pad:  u32::from_le(r.front_pad),
+                back_pad:   u32::from_le(r.back_pad),
+            }),
+            _                       => Err(bch_errcode::BCH_ERR_ENOENT_bkey_type_mismatch),
+        }
+    }
+
+    pub fn as_stripe(&self) -> Result<StripeInfo, bch_errcode> {
+        let s = match self.v() {
+            BkeyValC::stripe(s) => s,
+            _                   => return Err(bch_errcode::BCH_ERR_ENOENT_bkey_type_mismatch),
+        };
+        /* The pointers follow the 8 byte header: */
+        let ptrs = &self.val_bytes()[8..];
+
+        Ok(StripeInfo {
+            sectors:                u16::from_le(s.sectors),
+            algorithm:              s.algorithm,
+            nr_blocks:              s.nr_blocks,
+            nr_redundant:           s.nr_redundant,
+            csum_granularity_bits:  s.csum_granularity_bits,
+            csum_type:              s.csum_type,
+            blocks:                 ptrs.chunks_exact(8)
+                .take(s.nr_blocks as usize)
+                .map(|b| decode_ptr(LittleEndian::read_u64(b)))
+                .collect(),
+        })
+    }
+
+    /// Stored checksums, one per pointer, without reading any data; pointers
+    /// without a crc entry have no checksum (BCH_CSUM_none)
+    pub fn stored_checksums(&self) -> Vec<StoredChecksum> {
+        self.ptrs_decoded().iter()
+            .map(|p| {
+                let (csum_type, csum) = match p.crc {
+                    Some(crc)   => (crc.csum_type, crc.csum),
+                    None        => (0, c::bch_csum { lo: 0, hi: 0 }),
+                };
+
+                StoredChecksum { dev: p.ptr.dev, csum_type, csum }
+            })
+            .collect()
+    }
+}
+
+/// The checksum a pointer's data was written with, as stored in the key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StoredChecksum {
+    pub dev:        u8,
+    /// A bch_csum_type
+    pub csum_type:  u8,
+    pub csum:       c::bch_csum,
+}
+
+/// How much a key adds to the filesystem's space accounting
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AccountingContribution {
+    pub data_type:          Option<c::bch_data_type>,
+    /// Sectors on disk, summed over non-cached pointers
+    pub dirty_sectors:      u64,
+    pub cached_sectors:     u64,
+    /// Number of non-cached pointers
+    pub nr_replicas:        u32,
+    /// Reservations, which don't have pointers
+    pub reserved_sectors:   u64,
+}
+
+impl Fs {
+    /// Holes in file `inum` in the root subvolume - byte ranges with no data,
+    /// up to the end of the file - as (start, len)
+    pub fn holes(&self, inum: u64) -> Result<Vec<(u64, u64)>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let (snapshot, size) = trans.lockrestart_do(|| {
+            let snapshot = trans.subvol_snapshot(BCACHEFS_ROOT_SUBVOL)?;
+            Ok((snapshot, trans.inode_get(inum, snapshot)?.u.bi_size))
+        })?;
+        let mut ret = Vec::new();
+        let mut done = 0;
+
+        trans.for_each_key(c::btree_id::BTREE_ID_extents,
+            spos(inum, 0, snapshot),
+            spos(inum, u64::MAX, u32::MAX),
+            BtreeIterFlags::FILTER_SNAPSHOTS,
+            |k| {
+                if !k.is_extent_data() {
+                    return ControlFlow::Continue(());
+                }
+
+                let start = (k.k.p.offset - k.k.size as u64) << 9;
+                if start >= size {
+                    return ControlFlow::Break(());
+                }
+
+                if start > done {
+                    ret.push((done, start - done));
+                }
+                done = std::cmp::max(done, k.k.p.offset << 9);
+                ControlFlow::Continue(())
+            })?;
+
+        if done < size {
+            ret.push((done, size - done));
+        }
+
+        Ok(ret)
+    }
+
+    /// One record per pointer of every extent and indirect extent, in every
+    /// snapshot. Compressed extents are recorded at their compressed size,
+    /// starting where the compressed data starts, since that's what's on disk
+    pub fn physical_map(&self) -> Result<impl Iterator<Item = PhysRecord>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut ret = Vec::new();
+
+        for btree in [c::btree_id::BTREE_ID_extents, c::btree_id::BTREE_ID_reflink].iter().copied() {
+            trans.for_each_key(btree, POS_MIN, SPOS_MAX,
+                BtreeIterFlags::ALL_SNAPSHOTS|BtreeIterFlags::PREFETCH,
+                |k| {
+                    for p in k.ptrs_decoded() {
+                        let (offset, len) = match p.crc {
+                            Some(crc) if crc.is_compressed() =>
+                                (p.ptr.offset, crc.compressed_size as u64),
+                            Some(crc) => (p.ptr.offset + crc.offset as u64, k.k.size as u64),
+                            None => (p.ptr.offset, k.k.size as u64),
+                        };
+
+                        ret.push(PhysRecord {
+                            dev:        p.ptr.dev,
+                            offset,
+                            len,
+                            inode:      if btree == c::btree_id::BTREE_ID_extents { k.k.p.inode } else { 0 },
+                            snapshot:   k.k.p.snapshot,
+                            cached:     p.ptr.cached,
+                        });
+                    }
+                    ControlFlow::Continue(())
+                })?;
+        }
+
+        Ok(ret.into_iter())
+    }
+}
+
+/// A reflink_p key: the file's data lives in the reflink btree, starting at
+/// offset `idx`, for the size of this key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReflinkPtr {
+    pub idx:        u64,
+    /// The range of indirect extents this pointer holds a reference on
+    /// extends this many sectors before and after the range it points to
+    pub front_pad:  u32,
+    pub back_pad:   u32,
+}
+
+/// An erasure coded stripe
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StripeInfo {
+    /// Sectors per block
+    pub sectors:                u16,
+    pub algorithm:              u8,
+    pub nr_blocks:              u8,
+    /// Number of parity blocks, at the end of `blocks`
+    pub nr_redundant:           u8,
+    pub csum_granularity_bits:  u8,
+    /// A bch_csum_type
+    pub csum_type:              u8,
+    /// Where each block lives: data blocks, then parity blocks
+    pub blocks:                 Vec<ExtentPtr>,
+}
+
+impl StripeInfo {
+    pub fn nr_data(&self) -> u8 {
+        self.nr_blocks - self.nr_redundant
+    }
+}
+
+/// Where a pointer's data lives on disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PhysRecord {
+    pub dev:        u8,
+    /// In sectors, from the start of the device
+    pub offset:     u64,
+    /// In sectors
+    pub len:        u64,
+    /// Inode the data belongs to; 0 for indirect extents, which may be
+    /// referenced by many
+    pub inode:      u64,
+    pub snapshot:   u32,
+    pub cached:     bool,
+}
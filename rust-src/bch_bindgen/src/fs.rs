@@ -1,14 +1,33 @@
 use std::ffi::CString;
+use std::os::raw::c_void;
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 use crate::c;
-use crate::errcode::{bch_errcode, errptr_to_result};
+use crate::device::Device;
+use crate::errcode::{bch_errcode, errcode_to_result, errptr_to_result, io_err_to_errcode};
+use crate::recovery::{RecoveryPass, start_with_progress};
 
 pub struct Fs {
-    pub raw: *mut c::bch_fs,
+    pub raw:    *mut c::bch_fs,
+    /* Unregistered after the filesystem is stopped: */
+    _backends:  Vec<RegisteredBackend>,
+    /* The read io clock at open, and when, so io clock times can be converted to wall clock: */
+    opened:     (Instant, u64),
 }
 
 impl Fs {
+    fn from_raw(raw: *mut c::bch_fs, backends: Vec<RegisteredBackend>) -> Fs {
+        let clock = unsafe { (*raw).io_clock[0].now.counter } as u64;
+
+        Fs { raw, _backends: backends, opened: (Instant::now(), clock) }
+    }
+
+    pub(crate) fn opened(&self) -> (Instant, u64) {
+        self.opened
+    }
+
     pub fn open(devs: &Vec<PathBuf>, opts: c::bch_opts) -> Result<Fs, bch_errcode> {
         let devs: Vec<_> = devs.iter()
             .map(|i| CString::new(i.as_os_str().as_bytes()).unwrap().into_raw())
@@ -16,12 +35,241 @@ impl Fs {
 
         let ret = unsafe { c::bch2_fs_open(devs[..].as_ptr(), devs.len() as u32, opts) };
 
-        errptr_to_result(ret).map(|fs| Fs { raw: fs})
+        errptr_to_result(ret).map(|fs| Fs::from_raw(fs, Vec::new()))
+    }
+
+    /// Open a filesystem on devices that aren't block devices or files: all
+    /// IO to the devices is routed through `backends`, which the filesystem
+    /// owns until it's stopped
+    pub fn open_with_backends(backends: Vec<Box<dyn DeviceBackend>>, opts: c::bch_opts) -> Result<Fs, bch_errcode> {
+        let backends = backends.into_iter()
+            .map(RegisteredBackend::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        let devs: Vec<_> = backends.iter()
+            .map(|b| b.name.as_ptr() as *mut std::os::raw::c_char)
+            .collect();
+
+        let ret = unsafe { c::bch2_fs_open(devs[..].as_ptr(), devs.len() as u32, opts) };
+
+        errptr_to_result(ret).map(|fs| Fs::from_raw(fs, backends))
+    }
+
+    /// Open the filesystem that's mounted at or above `path`, by its member
+    /// devices as listed in /proc/mounts. The kernel has the devices open, so
+    /// `opts` will generally want noexcl, and nochanges or read_only.
+    ///
+    /// Fails with ENOENT if `path` isn't on a bcachefs filesystem.
+    pub fn open_for_path(path: &Path, opts: c::bch_opts) -> Result<Fs, bch_errcode> {
+        Fs::open(&mount_devs_for_path(path)?, opts)
+    }
+}
+
+/* /proc/mounts escapes space, tab, newline and backslash as octal: */
+fn unescape_mount_field(s: &str) -> String {
+    let mut ret = String::new();
+    let mut rest = s;
+
+    while let Some(i) = rest.find('\\') {
+        ret.push_str(&rest[..i]);
+        match rest.get(i + 1..i + 4).and_then(|o| u8::from_str_radix(o, 8).ok()) {
+            Some(c) => { ret.push(c as char); rest = &rest[i + 4..]; }
+            None    => { ret.push('\\'); rest = &rest[i + 1..]; }
+        }
+    }
+    ret.push_str(rest);
+    ret
+}
+
+/* (device, mountpoint, fstype) of each line of /proc/mounts: */
+fn read_mounts() -> std::io::Result<Vec<(String, PathBuf, String)>> {
+    Ok(std::fs::read_to_string("/proc/mounts")?
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(dev), Some(dir), Some(ty)) => Some((unescape_mount_field(dev),
+                                                          PathBuf::from(unescape_mount_field(dir)),
+                                                          ty.to_string())),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+/* A multi device filesystem is mounted as dev1:dev2:... */
+fn split_mount_devs(devs: &str) -> Vec<PathBuf> {
+    devs.split(':').map(PathBuf::from).collect()
+}
+
+/// Member devices of the mounted bcachefs filesystem containing `path`
+fn mount_devs_for_path(path: &Path) -> Result<Vec<PathBuf>, bch_errcode> {
+    let path = path.canonicalize().map_err(io_err_to_errcode)?;
+
+    /* Mounts over mounts: the last match with the longest prefix wins */
+    let mut best: Option<(PathBuf, Option<String>)> = None;
+    for (dev, dir, ty) in read_mounts().map_err(io_err_to_errcode)? {
+        if !path.starts_with(&dir) ||
+            best.as_ref().map_or(false, |(b, _)| b.as_os_str().len() > dir.as_os_str().len()) {
+            continue;
+        }
+        best = Some((dir, (ty == "bcachefs").then(|| dev)));
+    }
+
+    match best {
+        Some((_, Some(devs))) => Ok(split_mount_devs(&devs)),
+        _ => Err(bch_errcode::from_raw(libc::ENOENT)),
     }
 }
 
+/// A bcachefs filesystem mounted by the kernel
+#[derive(Clone, Debug)]
+pub struct MountedFs {
+    /// None if no member device's superblock could be read
+    pub uuid:       Option<uuid::Uuid>,
+    pub mountpoint: PathBuf,
+    pub devices:    Vec<PathBuf>,
+}
+
+/// Every bcachefs filesystem in /proc/mounts; the uuid is read from the
+/// superblock of the first member device that has a readable one. Empty if
+/// /proc/mounts can't be read.
+pub fn list_mounted() -> Vec<MountedFs> {
+    read_mounts().unwrap_or_default()
+        .into_iter()
+        .filter(|(_, _, ty)| ty == "bcachefs")
+        .map(|(dev, mountpoint, _)| {
+            let devices = split_mount_devs(&dev);
+            let uuid = devices.iter()
+                .find_map(|d| Device::read_super(d).ok())
+                .map(|sb| sb.uuid);
+
+            MountedFs { uuid, mountpoint, devices }
+        })
+        .collect()
+}
+
 impl Drop for Fs {
     fn drop(&mut self) {
         unsafe { c::bch2_fs_stop(self.raw) }
     }             
 }
+
+/// Storage for a device other than a block device or file - e.g. an in memory
+/// image, for testing. IO may be submitted from multiple threads at once.
+pub trait DeviceBackend: Send + Sync {
+    /// Returns the number of bytes read; short reads are retried
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize>;
+    /// Returns the number of bytes written; short writes are retried
+    fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<usize>;
+    /// Size of the device, in bytes
+    fn size(&self) -> u64;
+}
+
+struct RegisteredBackend {
+    name:       CString,
+    /* Boxed again so the C side has a thin pointer to it: */
+    _backend:   Box<Box<dyn DeviceBackend>>,
+}
+
+impl Drop for RegisteredBackend {
+    fn drop(&mut self) {
+        unsafe { c::blkdev_backend_unregister(self.name.as_ptr()) }
+    }
+}
+
+fn io_result_to_ssize(r: std::io::Result<usize>) -> c::ssize_t {
+    match r {
+        Ok(n)   => n as c::ssize_t,
+        Err(e)  => -(e.raw_os_error().unwrap_or(libc::EIO) as c::ssize_t),
+    }
+}
+
+unsafe extern "C" fn backend_read(p: *mut c_void, buf: *mut c_void, len: usize, offset: u64) -> c::ssize_t {
+    let backend = &*(p as *const Box<dyn DeviceBackend>);
+
+    io_result_to_ssize(backend.read_at(std::slice::from_raw_parts_mut(buf as *mut u8, len), offset))
+}
+
+unsafe extern "C" fn backend_write(p: *mut c_void, buf: *const c_void, len: usize, offset: u64) -> c::ssize_t {
+    let backend = &*(p as *const Box<dyn DeviceBackend>);
+
+    io_result_to_ssize(backend.write_at(std::slice::from_raw_parts(buf as *const u8, len), offset))
+}
+
+unsafe extern "C" fn backend_size(p: *mut c_void) -> u64 {
+    let backend = &*(p as *const Box<dyn DeviceBackend>);
+
+    backend.size()
+}
+
+impl RegisteredBackend {
+    fn new(backend: Box<dyn DeviceBackend>) -> Result<RegisteredBackend, bch_errcode> {
+        static NR: AtomicUsize = AtomicUsize::new(0);
+
+        let name = CString::new(format!("backend-{}", NR.fetch_add(1, Ordering::Relaxed))).unwrap();
+        let mut backend = Box::new(backend);
+        let ops = c::blkdev_backend {
+            private:    &mut *backend as *mut Box<dyn DeviceBackend> as *mut c_void,
+            read:       Some(backend_read),
+            write:      Some(backend_write),
+            size:       Some(backend_size),
+        };
+
+        errcode_to_result(unsafe { c::blkdev_backend_register(name.as_ptr(), &ops) })?;
+        Ok(RegisteredBackend { name, _backend: backend })
+    }
+}
+
+/// Opening a filesystem, with hooks beyond the options bch2_fs_open() takes
+pub struct FsOpenOptions<'a> {
+    opts:               c::bch_opts,
+    recovery_progress:  Option<Box<dyn FnMut(RecoveryPass, u64, u64) + Send + 'a>>,
+    btree_cache_size:   u64,
+}
+
+impl<'a> FsOpenOptions<'a> {
+    pub fn new(opts: c::bch_opts) -> FsOpenOptions<'a> {
+        FsOpenOptions { opts, recovery_progress: None, btree_cache_size: 0 }
+    }
+
+    /// Call `f(pass, done, total)` as recovery moves on to each pass, where
+    /// `done` is how far through the list of `total` passes recovery has got.
+    /// `f` is called from another thread, and progress isn't reported within a
+    /// pass; passes too short to be seen as they run are reported when
+    /// recovery finishes.
+    pub fn on_recovery_progress(mut self, f: impl FnMut(RecoveryPass, u64, u64) + Send + 'a) -> Self {
+        self.recovery_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Bound the btree node cache to `bytes`, evicting clean nodes to stay
+    /// under it; 0 for no limit beyond what memory pressure imposes. Eviction
+    /// is done in the background, so the cache can briefly go over, and the
+    /// reserve needed for btree updates and nodes that can't be evicted -
+    /// dirty or locked - are on top of the bound.
+    pub fn btree_cache_size(mut self, bytes: u64) -> Self {
+        self.btree_cache_size = bytes;
+        self
+    }
+
+    pub fn open(self, devs: &Vec<PathBuf>) -> Result<Fs, bch_errcode> {
+        let mut opts = self.opts;
+        let start = opts.nostart == 0;
+
+        /* Started here, once our hooks are in place: */
+        opts.nostart = 1;
+        opts.set_nostart_defined(1);
+
+        let fs = Fs::open(devs, opts)?;
+
+        unsafe { c::bch2_btree_cache_set_limit(fs.raw, self.btree_cache_size) };
+
+        if start {
+            match self.recovery_progress {
+                Some(mut f) => start_with_progress(fs.raw, &mut *f)?,
+                None        => { errcode_to_result(unsafe { c::bch2_fs_start(fs.raw) })?; }
+            }
+        }
+        Ok(fs)
+    }
+}
@@ -0,0 +1,85 @@
+use crate::c;
+use crate::errcode::{bch_errcode, errcode_to_result};
+use crate::fs::Fs;
+use std::time::{Duration, Instant};
+
+const FREE_SPACE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GcResult {
+    pub buckets_reclaimed:  u64,
+    pub sectors_reclaimed:  u64,
+}
+
+impl Fs {
+    /// (free buckets, used sectors) summed over all devices
+    fn gc_totals(&self) -> Result<(u64, u64), bch_errcode> {
+        let mut free_buckets = 0;
+        let mut used_sectors = 0;
+
+        for dev in self.devs() {
+            let u = self.dev_usage(dev)?;
+
+            free_buckets += u.d[c::bch_data_type::BCH_DATA_free as usize].buckets;
+            used_sectors += u.d.iter().map(|d| d.sectors).sum::<u64>();
+        }
+
+        Ok((free_buckets, used_sectors))
+    }
+
+    /// Run full mark and sweep gc, and report how much space it freed up:
+    /// bucket usage is recomputed from every key in every btree, and buckets
+    /// that nothing points to any more are freed - space leaked by updates
+    /// that didn't run their triggers. Mismatches are repaired according to the
+    /// fix_errors option the filesystem was opened with.
+    ///
+    /// Not safe against concurrent updates (it's why the gc thread only runs
+    /// gc_gens): only run this when nothing else is writing to the filesystem.
+    pub fn run_gc(&self) -> Result<GcResult, bch_errcode> {
+        let (free_before, used_before) = self.gc_totals()?;
+
+        errcode_to_result(unsafe { c::bch2_fs_gc(self.raw) })?;
+
+        let (free_after, used_after) = self.gc_totals()?;
+
+        Ok(GcResult {
+            buckets_reclaimed:  free_after.saturating_sub(free_before),
+            sectors_reclaimed:  used_before.saturating_sub(used_after),
+        })
+    }
+
+    /// Check allocation info (alloc keys against the freespace, need_discard
+    /// and bucket_gens btrees, and the LRU btree) without a full fsck;
+    /// inconsistencies are repaired according to the fix_errors option the
+    /// filesystem was opened with.
+    pub fn recalc_alloc(&self) -> Result<(), bch_errcode> {
+        errcode_to_result(unsafe { c::bch2_check_alloc_info(self.raw) })?;
+        errcode_to_result(unsafe { c::bch2_check_alloc_to_lru_refs(self.raw) })?;
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for at least `min_sectors` to be free, kicking
+    /// discards and invalidates (making cached buckets free) while we wait;
+    /// fails with ETIMEDOUT on timeout.
+    pub fn wait_for_free_space(&self, min_sectors: u64, timeout: Duration) -> Result<(), bch_errcode> {
+        let start = Instant::now();
+
+        loop {
+            if unsafe { c::bch2_fs_usage_read_short(self.raw) }.free >= min_sectors {
+                return Ok(());
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(bch_errcode::from_raw(libc::ETIMEDOUT));
+            }
+
+            unsafe {
+                c::bch2_do_discards(self.raw);
+                c::bch2_do_invalidates(self.raw);
+            }
+
+            std::thread::sleep(std::cmp::min(FREE_SPACE_POLL_INTERVAL, timeout - elapsed));
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use crate::c;
+use crate::bkey::{BkeySC, BkeyValC};
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::{bch_errcode, errcode_to_result};
+use crate::fs::Fs;
+use crate::{spos, POS_MIN, SPOS_MAX};
+use crate::subvolume::BCACHEFS_ROOT_SUBVOL;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::ops::ControlFlow;
+
+pub const BCACHEFS_ROOT_INO: u64 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FileType {
+    Reg,
+    Dir,
+    Symlink,
+    Fifo,
+    Chr,
+    Blk,
+    Sock,
+    Unknown,
+}
+
+impl FileType {
+    pub fn from_mode(mode: u32) -> FileType {
+        match mode & libc::S_IFMT {
+            libc::S_IFREG   => FileType::Reg,
+            libc::S_IFDIR   => FileType::Dir,
+            libc::S_IFLNK   => FileType::Symlink,
+            libc::S_IFIFO   => FileType::Fifo,
+            libc::S_IFCHR   => FileType::Chr,
+            libc::S_IFBLK   => FileType::Blk,
+            libc::S_IFSOCK  => FileType::Sock,
+            _               => FileType::Unknown,
+        }
+    }
+}
+
+/// On disk inode key format
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InodeFormat {
+    V1,
+    V2,
+    V3,
+}
+
+/// An unpacked inode, along with the format it was unpacked from
+pub struct Inode {
+    pub format: InodeFormat,
+    pub u:      c::bch_inode_unpacked,
+}
+
+impl Inode {
+    pub fn inum(&self) -> u64 {
+        self.u.bi_inum
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.u.bi_mode as u32
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType::from_mode(self.mode())
+    }
+
+    /// Journal sequence number of the last update; v1 inodes don't have it
+    pub fn journal_seq(&self) -> Option<u64> {
+        (self.format >= InodeFormat::V2).then(|| self.u.bi_journal_seq)
+    }
+
+    /// Only present in v3 inodes
+    pub fn version(&self) -> Option<u64> {
+        (self.format >= InodeFormat::V3).then(|| self.u.bi_version)
+    }
+
+    /// Only present in v3 inodes
+    pub fn nocow(&self) -> Option<u8> {
+        (self.format >= InodeFormat::V3).then(|| self.u.bi_nocow)
+    }
+
+    /// Subvolume this inode is the root of, if it's a subvolume root
+    pub fn subvol(&self) -> Option<u32> {
+        (self.u.bi_subvol != 0).then(|| self.u.bi_subvol)
+    }
+
+    pub fn parent_subvol(&self) -> Option<u32> {
+        (self.u.bi_parent_subvol != 0).then(|| self.u.bi_parent_subvol)
+    }
+
+    pub fn options(&self) -> InodeOptions {
+        let u = &self.u;
+        /* stored with a +1 bias, so that 0 means unset: */
+        macro_rules! opt {
+            ($f:expr) => { if $f != 0 { Some($f - 1) } else { None } };
+        }
+
+        InodeOptions {
+            data_checksum:          opt!(u.bi_data_checksum),
+            compression:            opt!(u.bi_compression),
+            project:                opt!(u.bi_project),
+            background_compression: opt!(u.bi_background_compression),
+            data_replicas:          opt!(u.bi_data_replicas),
+            promote_target:         opt!(u.bi_promote_target),
+            foreground_target:      opt!(u.bi_foreground_target),
+            background_target:      opt!(u.bi_background_target),
+            erasure_code:           opt!(u.bi_erasure_code),
+            nocow:                  opt!(u.bi_nocow),
+        }
+    }
+}
+
+/// Options set on an inode, overriding the filesystem's: as stored, so None
+/// means the filesystem option applies
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InodeOptions {
+    pub data_checksum:          Option<u8>,
+    pub compression:            Option<u8>,
+    pub project:                Option<u32>,
+    pub background_compression: Option<u8>,
+    pub data_replicas:          Option<u8>,
+    pub promote_target:         Option<u16>,
+    pub foreground_target:      Option<u16>,
+    pub background_target:      Option<u16>,
+    pub erasure_code:           Option<u16>,
+    pub nocow:                  Option<u8>,
+}
+
+impl<'a> BkeySC<'a> {
+    pub fn inode_format(&self) -> Option<InodeFormat> {
+        match self.v() {
+            BkeyValC::inode(_)      => Some(InodeFormat::V1),
+            BkeyValC::inode_v2(_)   => Some(InodeFormat::V2),
+            BkeyValC::inode_v3(_)   => Some(InodeFormat::V3),
+            _                       => None,
+        }
+    }
+
+    pub fn as_inode(&self) -> Result<Inode, bch_errcode> {
+        let format = self.inode_format().ok_or(bch_errcode::BCH_ERR_ENOENT_inode)?;
+        let mut u: c::bch_inode_unpacked = Default::default();
+
+        errcode_to_result(unsafe { c::bch2_inode_unpack(self.to_raw(), &mut u) })?;
+        Ok(Inode { format, u })
+    }
+
+    /// Generation number left behind by a deleted inode
+    pub fn as_inode_generation(&self) -> Result<u32, bch_errcode> {
+        match self.v() {
+            BkeyValC::inode_generation(g)   => Ok(u32::from_le(g.bi_generation)),
+            _                               => Err(bch_errcode::BCH_ERR_ENOENT_bkey_type_mismatch),
+        }
+    }
+
+    /// i_mode of an inode key, read directly from the key without unpacking
+    /// the varint fields
+    pub fn inode_mode(&self) -> Option<u32> {
+        match self.v() {
+            BkeyValC::inode(i)      => Some(u16::from_le(i.bi_mode) as u32),
+            BkeyValC::inode_v2(i)   => Some(u16::from_le(i.bi_mode) as u32),
+            BkeyValC::inode_v3(i)   => Some(((u64::from_le(i.bi_flags) >> 36) & 0xffff) as u32),
+            _                       => None,
+        }
+    }
+}
+
+impl<'f> BtreeTrans<'f> {
+    /// Mode of inode `inum` as seen from `snapshot`
+    pub fn inode_mode(&self, inum: u64, snapshot: u32) -> Result<u32, bch_errcode> {
+        let mut iter = BtreeIter::new(self, c::btree_id::BTREE_ID_inodes,
+            spos(0, inum, snapshot),
+            BtreeIterFlags::FILTER_SNAPSHOTS);
+        let k = iter.peek_slot()?;
+
+        k.inode_mode().ok_or(bch_errcode::BCH_ERR_ENOENT_inode)
+    }
+
+    pub fn inode_get(&self, inum: u64, snapshot: u32) -> Result<Inode, bch_errcode> {
+        let mut iter = BtreeIter::new(self, c::btree_id::BTREE_ID_inodes,
+            spos(0, inum, snapshot),
+            BtreeIterFlags::FILTER_SNAPSHOTS);
+
+        iter.peek_slot()?.as_inode()
+    }
+}
+
+impl Fs {
+    /// Convert an inode timestamp, in the filesystem's time units, to
+    /// nanoseconds since the epoch
+    pub fn time_to_ns(&self, t: u64) -> i64 {
+        let sb = unsafe { &(*self.raw).sb };
+
+        (t as i64 + sb.time_base_lo as i64) * sb.nsec_per_time_unit as i64
+    }
+
+    pub fn ns_to_time(&self, ns: i64) -> u64 {
+        let sb = unsafe { &(*self.raw).sb };
+
+        (ns / sb.nsec_per_time_unit as i64 - sb.time_base_lo as i64) as u64
+    }
+
+    pub fn inode_subvol(&self, subvol: u32, inum: u64) -> Result<Inode, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+
+        trans.lockrestart_do(|| {
+            let snapshot = trans.subvol_snapshot(subvol)?;
+            trans.inode_get(inum, snapshot)
+        })
+    }
+
+    /// Look up inode `inum` in the root subvolume
+    pub fn inode(&self, inum: u64) -> Result<Inode, bch_errcode> {
+        self.inode_subvol(BCACHEFS_ROOT_SUBVOL, inum)
+    }
+
+    /// The type of inode `inum` in the root subvolume
+    pub fn inode_type(&self, inum: u64) -> Result<FileType, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+
+        trans.lockrestart_do(|| {
+            let snapshot = trans.subvol_snapshot(BCACHEFS_ROOT_SUBVOL)?;
+            trans.inode_mode(inum, snapshot)
+        }).map(FileType::from_mode)
+    }
+
+    /// Set the link count of inode `inum` in the root subvolume to the number
+    /// of dirents pointing to it, returning (old, new); this walks every dirent
+    /// in the subvolume. Directories are rejected with EISDIR, since their link
+    /// counts come from their subdirectories.
+    pub fn fix_nlink(&self, inum: u64) -> Result<(u32, u32), bch_errcode> {
+        let mut old = 0;
+        let mut nlink = 0;
+
+        errcode_to_result(unsafe {
+            c::bch2_file_fix_nlink(self.raw, c::subvol_inum { subvol: BCACHEFS_ROOT_SUBVOL, inum },
+                &mut old, &mut nlink)
+        })?;
+
+        Ok((old, nlink))
+    }
+
+    /// Options set on the root inode of subvolume `subvol`
+    pub fn subvol_options(&self, subvol: u32) -> Result<InodeOptions, bch_errcode> {
+        let root = self.subvol_root(subvol)?;
+
+        Ok(self.inode_subvol(subvol, root)?.options())
+    }
+
+    /// Set option `name` on the root of subvolume `subvol`, e.g.
+    /// ("compression", Some("zstd")), as with the bcachefs.* xattrs; None
+    /// unsets it. Only inodes created afterwards inherit the change. Options
+    /// that aren't inode options, and project IDs, are EINVAL.
+    pub fn set_subvol_option(&self, subvol: u32, name: &str, value: Option<&str>) -> Result<(), bch_errcode> {
+        let root = self.subvol_root(subvol)?;
+        let name = CString::new(name).map_err(|_| bch_errcode::BCH_ERR_invalid)?;
+        let value = value
+            .map(|v| CString::new(v).map_err(|_| bch_errcode::BCH_ERR_invalid))
+            .transpose()?;
+
+        errcode_to_result(unsafe {
+            c::bch2_file_opt_set(self.raw, c::subvol_inum { subvol, inum: root },
+                name.as_ptr(), value.as_ref().map_or(std::ptr::null(), |v| v.as_ptr()))
+        })?;
+        Ok(())
+    }
+
+    fn subvol_root(&self, subvol: u32) -> Result<u64, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+
+        trans.lockrestart_do(|| trans.subvolume_get(subvol))
+            .map(|s| u64::from_le(s.inode))
+    }
+
+    /// Number of inodes of each type, over every subvolume and snapshot:
+    /// an inode that's in several snapshots is counted once, by inode number.
+    /// Types with no inodes are omitted.
+    pub fn inode_type_histogram(&self) -> Result<BTreeMap<FileType, u64>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut ret = BTreeMap::new();
+        let mut last_inum = None;
+
+        /* Keys are ordered by inode number, then snapshot: */
+        trans.for_each_key(c::btree_id::BTREE_ID_inodes, POS_MIN, SPOS_MAX,
+            BtreeIterFlags::ALL_SNAPSHOTS,
+            |k| {
+                if let Some(mode) = k.inode_mode() {
+                    if last_inum != Some(k.k.p.offset) {
+                        last_inum = Some(k.k.p.offset);
+                        *ret.entry(FileType::from_mode(mode)).or_insert(0) += 1;
+                    }
+                }
+                ControlFlow::Continue(())
+            })?;
+
+        Ok(ret)
+    }
+}
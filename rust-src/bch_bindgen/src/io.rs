@@ -0,0 +1,266 @@
+use crate::c;
+use crate::bkey::OwnedBkey;
+use crate::btree::{BtreeTrans, BtreeIterFlags};
+use crate::errcode::{bch_errcode, errcode_to_result};
+use crate::fs::Fs;
+use crate::spos;
+use crate::subvolume::BCACHEFS_ROOT_SUBVOL;
+use std::alloc::{self, Layout};
+use std::ops::ControlFlow;
+
+/// Heap buffer with the alignment the read path needs
+struct AlignedBuf {
+    ptr:    *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn new(size: usize, align: usize) -> AlignedBuf {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, layout }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/* Reads are split up so we don't allocate a bounce buffer the size of the file: */
+pub(crate) const READ_CHUNK: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgo {
+    Crc32c,
+    Crc64,
+    Xxhash,
+}
+
+enum HashState {
+    Crc32c(u32),
+    Crc64(u64),
+    Xxhash(c::xxh64_state),
+}
+
+impl HashState {
+    fn new(algo: HashAlgo) -> HashState {
+        match algo {
+            HashAlgo::Crc32c    => HashState::Crc32c(0),
+            HashAlgo::Crc64     => HashState::Crc64(0),
+            HashAlgo::Xxhash    => {
+                let mut state: c::xxh64_state = Default::default();
+                unsafe { c::xxh64_reset(&mut state, 0) };
+                HashState::Xxhash(state)
+            }
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        let p = data.as_ptr() as *const std::os::raw::c_void;
+
+        match self {
+            HashState::Crc32c(crc)  => *crc = unsafe { c::crc32c(*crc, p, data.len()) },
+            HashState::Crc64(crc)   => *crc = unsafe { c::crc64_be(*crc, p, data.len()) },
+            HashState::Xxhash(s)    => { unsafe { c::xxh64_update(s, p, data.len()) }; },
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            HashState::Crc32c(crc)  => crc.to_le_bytes().to_vec(),
+            HashState::Crc64(crc)   => crc.to_le_bytes().to_vec(),
+            HashState::Xxhash(s)    => unsafe { c::xxh64_digest(&s) }.to_le_bytes().to_vec(),
+        }
+    }
+}
+
+impl Fs {
+    pub fn block_bytes(&self) -> usize {
+        unsafe { (*self.raw).opts.block_size as usize }
+    }
+
+    /// Read file data starting at byte `offset` into `buf`, via the normal
+    /// read path: holes read as zeroes, and compressed and reflinked extents
+    /// are handled. Returns the number of bytes read, which is short at end
+    /// of file.
+    ///
+    /// Reflink pointers are followed to the indirect extents they point to by
+    /// bch2_read() - offset `idx` in the reflink btree corresponds to the start
+    /// of the reflink_p key. Their front and back padding only extends the
+    /// range they hold references on, and doesn't affect what's read.
+    pub fn read_file(&self, subvol: u32, inum: u64, offset: u64, buf: &mut [u8]) -> Result<usize, bch_errcode> {
+        let size = self.inode_subvol(subvol, inum)?.u.bi_size;
+        let len = std::cmp::min(buf.len() as u64, size.saturating_sub(offset)) as usize;
+        let block_bytes = self.block_bytes() as u64;
+        let mut bounce = AlignedBuf::new(READ_CHUNK, std::cmp::max(block_bytes as usize, 4096));
+        let mut done = 0;
+
+        while done < len {
+            let pos = offset + done as u64;
+            let start = pos & !(block_bytes - 1);
+            let skip = (pos - start) as usize;
+            let n = std::cmp::min(len - done, READ_CHUNK - skip);
+            let read_len = ((skip + n) as u64 + block_bytes - 1) & !(block_bytes - 1);
+            let b = bounce.as_mut_slice();
+
+            errcode_to_result(unsafe {
+                c::bch2_read_file(self.raw, c::subvol_inum { subvol, inum }, start,
+                    b.as_mut_ptr() as *mut std::os::raw::c_void, read_len as usize)
+            })?;
+
+            buf[done..done + n].copy_from_slice(&b[skip..skip + n]);
+            done += n;
+        }
+
+        Ok(len)
+    }
+
+    /// Write `data` to a file at byte `offset`, via the normal write path;
+    /// partial blocks at either end are merged with what's already there. The
+    /// file is extended if the write goes past the end.
+    pub fn write_file(&self, subvol: u32, inum: u64, offset: u64, data: &[u8]) -> Result<(), bch_errcode> {
+        self.write_file_to_target(subvol, inum, offset, data, 0)
+    }
+
+    /* A nonzero target overrides the file's foreground_target: */
+    pub(crate) fn write_file_to_target(&self, subvol: u32, inum: u64, offset: u64, data: &[u8],
+                                       target: u32) -> Result<(), bch_errcode> {
+        let block_bytes = self.block_bytes() as u64;
+        let mut bounce = AlignedBuf::new(READ_CHUNK, std::cmp::max(block_bytes as usize, 4096));
+        let mut done = 0;
+
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let start = pos & !(block_bytes - 1);
+            let skip = (pos - start) as usize;
+            let n = std::cmp::min(data.len() - done, READ_CHUNK - skip);
+            let write_len = (((skip + n) as u64 + block_bytes - 1) & !(block_bytes - 1)) as usize;
+            let b = &mut bounce.as_mut_slice()[..write_len];
+
+            if skip != 0 || skip + n != write_len {
+                b.fill(0);
+                self.read_file(subvol, inum, start, b)?;
+            }
+            b[skip..skip + n].copy_from_slice(&data[done..done + n]);
+
+            errcode_to_result(unsafe {
+                c::bch2_write_file(self.raw, c::subvol_inum { subvol, inum }, start,
+                    b.as_ptr() as *const std::os::raw::c_void, write_len, pos + n as u64, target)
+            })?;
+            done += n;
+        }
+
+        Ok(())
+    }
+
+    /// Reflink `sectors` of file `src` starting at sector `src_offset` into file
+    /// `dst` at sector `dst_offset`, both in subvolume `subvol`, as
+    /// FICLONERANGE does: the source's extents are moved to the reflink btree
+    /// if they aren't there already, and both files get reflink pointers to
+    /// them. `dst` is extended to `new_i_size` bytes if that's past its end.
+    /// Returns the number of sectors remapped.
+    pub fn remap_range(&self, subvol: u32, dst: u64, dst_offset: u64, src: u64, src_offset: u64,
+                       sectors: u64, new_i_size: u64) -> Result<u64, bch_errcode> {
+        let mut i_sectors_delta = 0;
+        let ret = unsafe {
+            c::bch2_remap_range(self.raw,
+                c::subvol_inum { subvol, inum: dst }, dst_offset,
+                c::subvol_inum { subvol, inum: src }, src_offset,
+                sectors, new_i_size, &mut i_sectors_delta)
+        };
+
+        if ret < 0 {
+            Err(bch_errcode::from_raw(-ret as i32))
+        } else {
+            Ok(ret as u64)
+        }
+    }
+
+    /// Hash of a file's logical contents - decompressed, with holes as zeroes
+    pub fn file_hash(&self, subvol: u32, inum: u64, algo: HashAlgo) -> Result<Vec<u8>, bch_errcode> {
+        let mut state = HashState::new(algo);
+        let mut buf = vec![0; READ_CHUNK];
+        let mut offset = 0;
+
+        loop {
+            let n = self.read_file(subvol, inum, offset, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+
+            state.update(&buf[..n]);
+            offset += n as u64;
+        }
+
+        Ok(state.finish())
+    }
+
+    /// Read every replica of file `inum`'s extents in the root subvolume
+    /// directly from disk and check them against their stored checksums. Data
+    /// reached through reflink pointers isn't checked, and neither is data
+    /// without checksums.
+    pub fn verify_inode(&self, inum: u64) -> Result<Vec<ChecksumError>, bch_errcode> {
+        let mut extents: Vec<OwnedBkey> = Vec::new();
+
+        {
+            let trans = BtreeTrans::new(self);
+            let snapshot = trans.lockrestart_do(|| trans.subvol_snapshot(BCACHEFS_ROOT_SUBVOL))?;
+
+            trans.for_each_key(c::btree_id::BTREE_ID_extents,
+                spos(inum, 0, snapshot), spos(inum, u64::MAX, snapshot),
+                BtreeIterFlags::FILTER_SNAPSHOTS,
+                |k| {
+                    if !k.ptrs_decoded().is_empty() {
+                        extents.push(k.to_owned_bkey());
+                    }
+                    ControlFlow::Continue(())
+                })?;
+        }
+
+        let mut ret = Vec::new();
+
+        for e in &extents {
+            let k = e.as_bkey_sc();
+            let (mut bad, mut io_err) = (0u64, 0u64);
+
+            errcode_to_result(unsafe {
+                c::bch2_extent_verify_checksums(self.raw, k.to_raw(), &mut bad, &mut io_err)
+            })?;
+
+            for dev in 0..64u8 {
+                if (bad | io_err) & (1 << dev) != 0 {
+                    ret.push(ChecksumError {
+                        offset:     (k.k.p.offset - k.k.size as u64) << 9,
+                        len:        (k.k.size as u64) << 9,
+                        dev,
+                        io_error:   io_err & (1 << dev) != 0,
+                    });
+                }
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+/// A replica of an extent that failed verification
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChecksumError {
+    /// Start of the extent, in bytes
+    pub offset:     u64,
+    /// Length of the extent, in bytes
+    pub len:        u64,
+    pub dev:        u8,
+    /// The device couldn't be read, rather than having bad data
+    pub io_error:   bool,
+}
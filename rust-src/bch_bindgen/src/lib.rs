@@ -6,6 +6,20 @@ pub mod keyutils;
 pub mod rs;
 pub mod fs;
 pub mod opts;
+pub mod device;
+pub mod sb;
+pub mod gc;
+pub mod extents;
+pub mod subvolume;
+pub mod inode;
+pub mod io;
+pub mod dirent;
+pub mod xattr;
+pub mod send;
+pub mod receive;
+pub mod rebalance;
+pub mod recovery;
+pub mod logged_ops;
 pub use paste::paste;
 
 pub mod c {
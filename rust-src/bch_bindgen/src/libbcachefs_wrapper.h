@@ -1,16 +1,30 @@
 #include "../libbcachefs/super-io.h"
+#include "../libbcachefs/alloc_background.h"
 #include "../libbcachefs/checksum.h"
+#include "../libbcachefs/compress.h"
 #include "../libbcachefs/bcachefs_format.h"
 #include "../libbcachefs/btree_cache.h"
+#include "../libbcachefs/btree_gc.h"
+#include "../libbcachefs/buckets.h"
 #include "../libbcachefs/btree_iter.h"
+#include "../libbcachefs/btree_update_interior.h"
+#include "../libbcachefs/btree_write_buffer.h"
 #include "../libbcachefs/debug.h"
 #include "../libbcachefs/errcode.h"
+#include "../libbcachefs/inode.h"
 #include "../libbcachefs/error.h"
 #include "../libbcachefs/opts.h"
+#include "../libbcachefs/recovery.h"
+#include "../libbcachefs/reflink.h"
+#include "../libbcachefs/subvolume.h"
+#include "../libbcachefs/super.h"
 #include "../libbcachefs.h"
 #include "../crypto.h"
 #include "../include/linux/bio.h"
 #include "../include/linux/blkdev.h"
+#include "../include/linux/crc64.h"
+#include "../include/linux/xxhash.h"
+#include <sodium/crypto_hash_sha256.h>
 
 
 #define MARK_FIX_753(req_name) const blk_mode_t Fix753_##req_name = req_name;
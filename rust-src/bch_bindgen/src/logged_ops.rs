@@ -0,0 +1,86 @@
+use crate::c;
+use crate::bkey::{BkeySC, BkeyValC, OwnedBkey};
+use crate::btree::{BtreeTrans, BtreeIterFlags};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::{POS_MIN, SPOS_MAX};
+use std::ops::ControlFlow;
+
+/// An operation in the logged_ops btree: one that was started but not
+/// finished, which recovery will finish
+#[derive(Clone, Debug)]
+pub struct LoggedOp {
+    key:    OwnedBkey,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoggedOpTruncate {
+    pub subvol:     u32,
+    pub inum:       u64,
+    /// In bytes
+    pub new_i_size: u64,
+}
+
+/// A finsert/fcollapse, moving the extents at `src_offset` and after to
+/// `dst_offset`; offsets are in sectors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoggedOpFinsert {
+    /// How far it got: 0 start, 1 shifting extents, 2 finish
+    pub state:      u8,
+    pub subvol:     u32,
+    pub inum:       u64,
+    pub dst_offset: u64,
+    pub src_offset: u64,
+    /// How far extents have been shifted
+    pub pos:        u64,
+}
+
+impl LoggedOp {
+    pub fn key(&self) -> BkeySC<'_> {
+        self.key.as_bkey_sc()
+    }
+
+    pub fn as_truncate(&self) -> Result<LoggedOpTruncate, bch_errcode> {
+        match self.key().v() {
+            BkeyValC::logged_op_truncate(op)    => Ok(LoggedOpTruncate {
+                subvol:     u32::from_le(op.subvol),
+                inum:       u64::from_le(op.inum),
+                new_i_size: u64::from_le(op.new_i_size),
+            }),
+            _                                   => Err(bch_errcode::BCH_ERR_ENOENT_bkey_type_mismatch),
+        }
+    }
+
+    pub fn as_finsert(&self) -> Result<LoggedOpFinsert, bch_errcode> {
+        match self.key().v() {
+            BkeyValC::logged_op_finsert(op)     => Ok(LoggedOpFinsert {
+                state:      op.state,
+                subvol:     u32::from_le(op.subvol),
+                inum:       u64::from_le(op.inum),
+                dst_offset: u64::from_le(op.dst_offset),
+                src_offset: u64::from_le(op.src_offset),
+                pos:        u64::from_le(op.pos),
+            }),
+            _                                   => Err(bch_errcode::BCH_ERR_ENOENT_bkey_type_mismatch),
+        }
+    }
+}
+
+impl Fs {
+    /// Operations in the logged_ops btree. Recovery finishes these when the
+    /// filesystem is opened, so they'll only be seen after opening with
+    /// norecovery, or while an operation is in progress.
+    pub fn logged_ops(&self) -> Result<Vec<LoggedOp>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut ret = Vec::new();
+
+        trans.for_each_key(c::btree_id::BTREE_ID_logged_ops, POS_MIN, SPOS_MAX,
+            BtreeIterFlags::empty(),
+            |k| {
+                ret.push(LoggedOp { key: k.to_owned_bkey() });
+                ControlFlow::Continue(())
+            })?;
+
+        Ok(ret)
+    }
+}
@@ -1,3 +1,8 @@
+use crate::c;
+use crate::errcode::{bch_errcode, errcode_to_result, BchError};
+use crate::fs::Fs;
+use std::ffi::CString;
+
 #[macro_export]
 macro_rules! opt_set {
     ($opts:ident, $n:ident, $v:expr) => {
@@ -33,3 +38,136 @@ macro_rules! opt_get {
         }
     };
 }
+
+/// What the filesystem does when it hits an inconsistency
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorAction {
+    Continue,
+    ReadOnly,
+    Panic,
+}
+
+impl From<ErrorAction> for c::bch_error_actions {
+    fn from(action: ErrorAction) -> Self {
+        match action {
+            ErrorAction::Continue   => c::bch_error_actions::BCH_ON_ERROR_continue,
+            ErrorAction::ReadOnly   => c::bch_error_actions::BCH_ON_ERROR_ro,
+            ErrorAction::Panic      => c::bch_error_actions::BCH_ON_ERROR_panic,
+        }
+    }
+}
+
+impl Fs {
+    /// The options currently in effect on this filesystem
+    pub fn effective_options(&self) -> c::bch_opts {
+        unsafe { (*self.raw).opts }
+    }
+
+    pub fn error_action(&self) -> ErrorAction {
+        let opts = self.effective_options();
+        let v = unsafe { c::bch2_opt_get_by_id(&opts, c::bch_opt_id::Opt_errors) };
+
+        match v {
+            0 => ErrorAction::Continue,
+            1 => ErrorAction::ReadOnly,
+            _ => ErrorAction::Panic,
+        }
+    }
+
+    /// Change the error action of the running filesystem; this is not
+    /// persisted to the superblock
+    pub fn set_error_action(&self, action: ErrorAction) -> Result<(), bch_errcode> {
+        let v: c::bch_error_actions = action.into();
+
+        errcode_to_result(unsafe {
+            c::bch2_opt_check_may_set(self.raw, c::bch_opt_id::Opt_errors as i32, v as u64)
+        })?;
+
+        unsafe { c::bch2_opt_set_by_id(&mut (*self.raw).opts, c::bch_opt_id::Opt_errors, v as u64) };
+        Ok(())
+    }
+
+    /// Change the foreground (or background) compression of the running
+    /// filesystem, e.g. "zstd" or "lz4:3"; like the error action, this is not
+    /// persisted to the superblock.
+    ///
+    /// Compression types this build doesn't know about or can't do, and levels
+    /// the type doesn't have, are reported as `BchError::Unsupported`.
+    pub fn set_compression(&self, compression: &str, background: bool) -> Result<(), BchError> {
+        let id = if background {
+            c::bch_opt_id::Opt_background_compression
+        } else {
+            c::bch_opt_id::Opt_compression
+        };
+
+        let type_str = compression.split(':').next().unwrap_or("");
+        let unsupported = || BchError::Unsupported {
+            feature: format!("compression type {:?}", type_str),
+        };
+
+        let type_cstr = CString::new(type_str).map_err(|_| unsupported())?;
+        let t = unsafe {
+            c::match_string(c::bch2_compression_opts.as_ptr(), (-(1 as isize)) as usize, type_cstr.as_ptr())
+        };
+        if t < 0 {
+            return Err(unsupported());
+        }
+
+        /* With the type known good, parsing only fails on the level: */
+        let val = CString::new(compression).map_err(|_| unsupported())?;
+        let mut v: u64 = 0;
+        if unsafe { c::bch2_opt_compression_parse(self.raw, val.as_ptr(), &mut v, std::ptr::null_mut()) } < 0 {
+            return Err(BchError::Unsupported {
+                feature: format!("compression level {:?} for {}", compression.splitn(2, ':').nth(1).unwrap_or(""), type_str),
+            });
+        }
+
+        /* Sets up the compression workspace, if we didn't have one: */
+        errcode_to_result(unsafe { c::bch2_opt_check_may_set(self.raw, id as i32, v) })?;
+
+        /* and there's none for types this build can't compress with: */
+        let ty = unsafe { c::bch2_compression_opt_to_type(v as u32) };
+        if ty != c::bch_compression_type::BCH_COMPRESSION_TYPE_none &&
+            unsafe { (*self.raw).compress_workspace[ty as usize].elements.is_null() } {
+            return Err(unsupported());
+        }
+
+        unsafe { c::bch2_opt_set_by_id(&mut (*self.raw).opts, id, v) };
+        Ok(())
+    }
+
+    /// Set a data placement target of the running filesystem to `label` - a
+    /// disk group label, e.g. "ssd" or "hdd.fast", or a device; unlike the
+    /// error action and compression, this is persisted to the superblock.
+    ///
+    /// Setting the background target queues a rebalance scan, so existing
+    /// data is moved as well as new writes.
+    pub fn set_target(&self, which: TargetKind, label: &str) -> Result<(), bch_errcode> {
+        let name = CString::new(which.opt_name()).unwrap();
+        let label = CString::new(label).map_err(|_| bch_errcode::BCH_ERR_invalid)?;
+
+        errcode_to_result(unsafe { c::bch2_fs_opt_set(self.raw, name.as_ptr(), label.as_ptr()) })?;
+        Ok(())
+    }
+}
+
+/// Which of the data placement targets to set, for [`Fs::set_target`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TargetKind {
+    /// Where new writes go
+    Foreground,
+    /// Where rebalance moves data to after it's written
+    Background,
+    /// Where data is cached when it's read
+    Promote,
+}
+
+impl TargetKind {
+    fn opt_name(self) -> &'static str {
+        match self {
+            TargetKind::Foreground  => "foreground_target",
+            TargetKind::Background  => "background_target",
+            TargetKind::Promote     => "promote_target",
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use crate::c;
+use crate::btree::{BtreeTrans, BtreeIterFlags};
+use crate::errcode::{bch_errcode, errcode_to_result};
+use crate::fs::Fs;
+use crate::{POS_MIN, SPOS_MAX};
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+const REBALANCE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl Fs {
+    /// Number of entries in the rebalance_work btree: extents that need to be
+    /// moved or recompressed, and pending scans queued by option changes
+    pub fn rebalance_work(&self) -> Result<u64, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut nr = 0;
+
+        trans.for_each_key(c::btree_id::BTREE_ID_rebalance_work,
+            POS_MIN, SPOS_MAX,
+            BtreeIterFlags::ALL_SNAPSHOTS,
+            |_| {
+                nr += 1;
+                ControlFlow::Continue(())
+            })?;
+
+        Ok(nr)
+    }
+
+    pub fn has_rebalance_work(&self) -> Result<bool, bch_errcode> {
+        Ok(self.rebalance_work()? != 0)
+    }
+
+    /// Wake up the rebalance thread and wait up to `timeout` for there to be
+    /// no rebalance work left, calling `progress(done, total)` at each poll;
+    /// `total` is the most work seen outstanding, since more can be queued
+    /// while we wait. Fails with ETIMEDOUT on timeout.
+    ///
+    /// The filesystem must be read-write, so that the rebalance thread is
+    /// running.
+    pub fn rebalance_and_wait(&self, timeout: Duration, mut progress: impl FnMut(u64, u64)) -> Result<(), bch_errcode> {
+        errcode_to_result(unsafe { c::bch2_rebalance_kick(self.raw) })?;
+
+        let start = Instant::now();
+        let mut total = 0;
+        loop {
+            let remaining = self.rebalance_work()?;
+
+            total = std::cmp::max(total, remaining);
+            progress(total - remaining, total);
+
+            if remaining == 0 {
+                return Ok(());
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(bch_errcode::from_raw(libc::ETIMEDOUT));
+            }
+
+            std::thread::sleep(std::cmp::min(REBALANCE_POLL_INTERVAL, timeout - elapsed));
+        }
+    }
+}
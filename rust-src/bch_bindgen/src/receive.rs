@@ -0,0 +1,497 @@
+use crate::c;
+use crate::bkey::BkeyValC;
+use crate::btree::{BtreeTrans, BtreeIterFlags};
+use crate::errcode::{bch_errcode, errcode_to_result, io_err_to_errcode};
+use crate::fs::Fs;
+use crate::send::{SendRecord, MAX_RECORD, SEND_MAGIC, SEND_VERSION, RECEIVED_INUM, RECEIVED_SNAPSHOT};
+use crate::subvolume::BCACHEFS_ROOT_SUBVOL;
+use crate::xattr::KEY_TYPE_XATTR_INDEX_TRUSTED;
+use crate::{spos, POS_MIN, SPOS_MAX};
+use byteorder::{LittleEndian, ByteOrder};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io::{self, Read};
+use std::ops::ControlFlow;
+
+/* A malformed or truncated stream: */
+const INVALID: bch_errcode = bch_errcode::BCH_ERR_invalid;
+
+fn read_exact<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<(), bch_errcode> {
+    r.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof    => INVALID,
+        _                               => io_err_to_errcode(e),
+    })
+}
+
+fn read_record<R: Read>(r: &mut R) -> Result<(SendRecord, Vec<u8>), bch_errcode> {
+    let mut hdr = [0; 5];
+    read_exact(r, &mut hdr)?;
+
+    let ty = SendRecord::from_u8(hdr[0]).ok_or(INVALID)?;
+    let len = LittleEndian::read_u32(&hdr[1..]) as usize;
+    if len > MAX_RECORD {
+        return Err(INVALID);
+    }
+
+    let mut payload = vec![0; len];
+    read_exact(r, &mut payload)?;
+
+    Ok((ty, payload))
+}
+
+/// Cursor over a record's payload
+struct Payload<'a> {
+    b:  &'a [u8],
+}
+
+impl<'a> Payload<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], bch_errcode> {
+        if self.b.len() < n {
+            return Err(INVALID);
+        }
+
+        let (ret, rest) = self.b.split_at(n);
+        self.b = rest;
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8, bch_errcode> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, bch_errcode> {
+        Ok(LittleEndian::read_u32(self.bytes(4)?))
+    }
+
+    fn u64(&mut self) -> Result<u64, bch_errcode> {
+        Ok(LittleEndian::read_u64(self.bytes(8)?))
+    }
+
+    fn i64(&mut self) -> Result<i64, bch_errcode> {
+        Ok(LittleEndian::read_i64(self.bytes(8)?))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.b)
+    }
+}
+
+struct Link {
+    dir:    u64,
+    target: u64,
+    name:   Vec<u8>,
+}
+
+struct Unlink {
+    dir:    u64,
+    name:   Vec<u8>,
+}
+
+struct XattrOp {
+    inum:   u64,
+    x_type: u8,
+    name:   Vec<u8>,
+    /// None to remove
+    value:  Option<Vec<u8>>,
+}
+
+/// A dirent that was removed, along with the inode it currently points to
+struct Removed {
+    dir:    c::subvol_inum,
+    name:   Vec<u8>,
+    target: u64,
+}
+
+struct Receiver<'f> {
+    fs:     &'f Fs,
+    subvol: u32,
+    /// Sender's inode numbers to ours
+    inums:  HashMap<u64, u64>,
+}
+
+impl<'f> Receiver<'f> {
+    fn local(&self, inum: u64) -> Option<c::subvol_inum> {
+        self.inums.get(&inum).map(|&inum| c::subvol_inum { subvol: self.subvol, inum })
+    }
+
+    fn lookup(&self, dir: c::subvol_inum, name: &[u8]) -> Result<Option<u64>, bch_errcode> {
+        let mut target: c::subvol_inum = Default::default();
+
+        match errcode_to_result(unsafe {
+            c::bch2_file_lookup(self.fs.raw, dir, name.as_ptr(), name.len() as u32, &mut target)
+        }) {
+            Ok(_)                                       => Ok(Some(target.inum)),
+            Err(e) if e.matches_errno(libc::ENOENT)     => Ok(None),
+            Err(e)                                      => Err(e),
+        }
+    }
+
+    fn unlink(&self, dir: c::subvol_inum, name: &[u8]) -> Result<(), bch_errcode> {
+        match errcode_to_result(unsafe {
+            c::bch2_file_unlink(self.fs.raw, dir, name.as_ptr(), name.len() as u32)
+        }) {
+            Err(e) if e.matches_errno(libc::ENOENT)     => Ok(()),
+            r                                           => r.map(|_| ()),
+        }
+    }
+
+    fn xattr_set(&self, inum: c::subvol_inum, x_type: u8, name: &[u8], value: Option<&[u8]>) -> Result<(), bch_errcode> {
+        let name = CString::new(name).map_err(|_| INVALID)?;
+        let (p, len) = match value {
+            Some(v) => (v.as_ptr() as *const std::os::raw::c_void, v.len()),
+            None    => (std::ptr::null(), 0),
+        };
+
+        errcode_to_result(unsafe {
+            c::bch2_file_xattr_set(self.fs.raw, inum, x_type as i32, name.as_ptr(), p, len)
+        })?;
+        Ok(())
+    }
+
+    fn set_received_inum(&self, inum: u64, sender_inum: u64) -> Result<(), bch_errcode> {
+        self.xattr_set(c::subvol_inum { subvol: self.subvol, inum },
+            KEY_TYPE_XATTR_INDEX_TRUSTED, RECEIVED_INUM, Some(&sender_inum.to_le_bytes()))
+    }
+
+    /// Recover the inode number mapping from a previous receive
+    fn load_inums(&mut self) -> Result<(), bch_errcode> {
+        let trans = BtreeTrans::new(self.fs);
+        let snapshot = trans.lockrestart_do(|| trans.subvol_snapshot(self.subvol))?;
+        let inums = &mut self.inums;
+
+        trans.for_each_key(c::btree_id::BTREE_ID_xattrs, spos(0, 0, snapshot), SPOS_MAX,
+            BtreeIterFlags::PREFETCH|BtreeIterFlags::FILTER_SNAPSHOTS,
+            |k| {
+                if let Ok(x) = k.as_xattr() {
+                    if x.x_type == KEY_TYPE_XATTR_INDEX_TRUSTED &&
+                        x.name == RECEIVED_INUM &&
+                        x.value.len() == 8 {
+                        inums.insert(LittleEndian::read_u64(x.value), x.inum);
+                    }
+                }
+                ControlFlow::Continue(())
+            })
+    }
+
+    fn link(&mut self, dir: c::subvol_inum, l: &Link,
+            inodes: &HashMap<u64, c::bch_inode_unpacked>,
+            removed: &mut Vec<Removed>) -> Result<(), bch_errcode> {
+        let fs = self.fs.raw;
+        let name = &l.name;
+        let existing = self.lookup(dir, name)?;
+        let target = self.inums.get(&l.target).copied();
+
+        if existing.is_some() && existing == target {
+            return Ok(());
+        }
+
+        if let Some(target) = target {
+            /*
+             * An inode we already have: if one of its old dirents is going
+             * away it was moved, which has to be a rename - directories can't
+             * be unlinked while they have children:
+             */
+            if let Some(i) = removed.iter().position(|r| r.target == target) {
+                let src = removed.remove(i);
+
+                errcode_to_result(unsafe {
+                    c::bch2_file_rename(fs,
+                        src.dir, src.name.as_ptr(), src.name.len() as u32,
+                        dir, name.as_ptr(), name.len() as u32,
+                        existing.is_some())
+                })?;
+                return Ok(());
+            }
+
+            if existing.is_some() {
+                self.unlink(dir, name)?;
+            }
+
+            errcode_to_result(unsafe {
+                c::bch2_file_link(fs, dir, name.as_ptr(), name.len() as u32, target)
+            })?;
+            return Ok(());
+        }
+
+        if existing.is_some() {
+            self.unlink(dir, name)?;
+        }
+
+        let u = inodes.get(&l.target).ok_or(INVALID)?;
+        let mut inum = 0;
+
+        errcode_to_result(unsafe {
+            c::bch2_file_create(fs, dir, name.as_ptr(), name.len() as u32,
+                u.bi_mode as u32, u.bi_dev, &mut inum)
+        })?;
+
+        self.inums.insert(l.target, inum);
+        self.set_received_inum(inum, l.target)
+    }
+
+    /// Apply dirent changes: new dirents are created, in whatever order makes
+    /// their parent directories exist first, before removed dirents are
+    /// unlinked, children before parents
+    fn apply_dirents(&mut self,
+                     inodes: &HashMap<u64, c::bch_inode_unpacked>,
+                     links: Vec<Link>,
+                     unlinks: Vec<Unlink>) -> Result<(), bch_errcode> {
+        let mut removed = Vec::new();
+
+        for u in unlinks {
+            if let Some(dir) = self.local(u.dir) {
+                if let Some(target) = self.lookup(dir, &u.name)? {
+                    removed.push(Removed { dir, name: u.name, target });
+                }
+            }
+        }
+
+        let mut pending = links;
+        while !pending.is_empty() {
+            let nr = pending.len();
+            let mut deferred = Vec::new();
+
+            for l in pending {
+                match self.local(l.dir) {
+                    Some(dir)   => self.link(dir, &l, inodes, &mut removed)?,
+                    None        => deferred.push(l),
+                }
+            }
+
+            /* Dirents in directories that never got created: */
+            if deferred.len() == nr {
+                return Err(INVALID);
+            }
+            pending = deferred;
+        }
+
+        let mut pending = removed;
+        while !pending.is_empty() {
+            let nr = pending.len();
+            let mut deferred = Vec::new();
+            let mut err = INVALID;
+
+            for r in pending {
+                match self.unlink(r.dir, &r.name) {
+                    Err(e) if e.matches_errno(libc::ENOTEMPTY) => {
+                        err = e;
+                        deferred.push(r);
+                    }
+                    ret => ret?,
+                }
+            }
+
+            if deferred.len() == nr {
+                return Err(err);
+            }
+            pending = deferred;
+        }
+
+        Ok(())
+    }
+}
+
+impl Fs {
+    /// Find the subvolume a send stream of `snapshot` was received into
+    fn received_subvol(&self, snapshot: u32) -> Result<u32, bch_errcode> {
+        use c::btree_id::*;
+
+        let trans = BtreeTrans::new(self);
+        let mut subvols = Vec::new();
+
+        trans.for_each_key(BTREE_ID_subvolumes, POS_MIN, SPOS_MAX, BtreeIterFlags::empty(),
+            |k| {
+                if let BkeyValC::subvolume(s) = k.v() {
+                    subvols.push((k.k.p.offset as u32, u32::from_le(s.snapshot), u64::from_le(s.inode)));
+                }
+                ControlFlow::Continue(())
+            })?;
+
+        for (subvol, subvol_snapshot, root) in subvols {
+            let mut found = false;
+
+            trans.for_each_key(BTREE_ID_xattrs, spos(root, 0, subvol_snapshot),
+                spos(root, u64::MAX, u32::MAX),
+                BtreeIterFlags::FILTER_SNAPSHOTS,
+                |k| match k.as_xattr() {
+                    Ok(x) if x.x_type == KEY_TYPE_XATTR_INDEX_TRUSTED &&
+                        x.name == RECEIVED_SNAPSHOT &&
+                        x.value == snapshot.to_le_bytes() => {
+                        found = true;
+                        ControlFlow::Break(())
+                    }
+                    _ => ControlFlow::Continue(()),
+                })?;
+
+            if found {
+                return Ok(subvol);
+            }
+        }
+
+        Err(bch_errcode::BCH_ERR_ENOENT_subvolume)
+    }
+
+    fn inode_from_payload(&self, p: &mut Payload) -> Result<(u64, c::bch_inode_unpacked), bch_errcode> {
+        let mut u: c::bch_inode_unpacked = Default::default();
+        let inum = p.u64()?;
+
+        u.bi_mode   = p.u32()? as u16;
+        u.bi_uid    = p.u32()?;
+        u.bi_gid    = p.u32()?;
+        u.bi_nlink  = p.u32()?;
+        u.bi_dev    = p.u32()?;
+        u.bi_size   = p.u64()?;
+        u.bi_atime  = self.ns_to_time(p.i64()?);
+        u.bi_mtime  = self.ns_to_time(p.i64()?);
+        u.bi_ctime  = self.ns_to_time(p.i64()?);
+        u.bi_otime  = self.ns_to_time(p.i64()?);
+
+        Ok((inum, u))
+    }
+
+    /// Apply a send stream from Fs::send(), creating a new subvolume for it
+    /// in directory `into_parent` of the root subvolume, named after the
+    /// snapshot that was sent. Returns the new subvolume's ID.
+    ///
+    /// An incremental stream is applied to a snapshot of the subvolume its
+    /// parent snapshot was received into. Each operation is its own
+    /// transaction, so this isn't atomic - but if receiving fails partway, the
+    /// new subvolume is deleted again, leaving nothing behind.
+    pub fn receive<R: Read>(&self, into_parent: u64, stream: &mut R) -> Result<u32, bch_errcode> {
+        let mut hdr = [0; 28];
+        read_exact(stream, &mut hdr)?;
+
+        let mut h = Payload { b: &hdr };
+        if h.bytes(8)? != SEND_MAGIC || h.u32()? != SEND_VERSION {
+            return Err(INVALID);
+        }
+
+        let snapshot    = h.u32()?;
+        let parent_snap = h.u32()?;
+        let root        = h.u64()?;
+
+        /* Metadata comes first, and isn't big - data is applied as we read it: */
+        let mut inodes      = HashMap::new();
+        let mut destroys    = Vec::new();
+        let mut links       = Vec::new();
+        let mut unlinks     = Vec::new();
+        let mut xattrs      = Vec::new();
+
+        let mut rec = loop {
+            let (ty, payload) = read_record(stream)?;
+            let mut p = Payload { b: &payload };
+
+            match ty {
+                SendRecord::Inode => {
+                    let (inum, u) = self.inode_from_payload(&mut p)?;
+                    inodes.insert(inum, u);
+                }
+                SendRecord::Destroy => destroys.push(p.u64()?),
+                SendRecord::Link => {
+                    let dir     = p.u64()?;
+                    let target  = p.u64()?;
+                    let _d_type = p.u8()?;
+
+                    links.push(Link { dir, target, name: p.rest().to_vec() });
+                }
+                SendRecord::Unlink => {
+                    let dir = p.u64()?;
+
+                    unlinks.push(Unlink { dir, name: p.rest().to_vec() });
+                }
+                SendRecord::XattrSet => {
+                    let inum        = p.u64()?;
+                    let x_type      = p.u8()?;
+                    let name_len    = p.u8()? as usize;
+                    let name        = p.bytes(name_len)?.to_vec();
+
+                    xattrs.push(XattrOp { inum, x_type, name, value: Some(p.rest().to_vec()) });
+                }
+                SendRecord::XattrRemove => {
+                    let inum    = p.u64()?;
+                    let x_type  = p.u8()?;
+
+                    xattrs.push(XattrOp { inum, x_type, name: p.rest().to_vec(), value: None });
+                }
+                SendRecord::Data | SendRecord::End => break (ty, payload),
+            }
+        };
+
+        let snapshot_src = match parent_snap {
+            0 => 0,
+            p => self.received_subvol(p)?,
+        };
+
+        let name = snapshot.to_string();
+        let mut subvol = 0;
+        let mut local_root = 0;
+
+        errcode_to_result(unsafe {
+            c::bch2_file_create_subvol(self.raw,
+                c::subvol_inum { subvol: BCACHEFS_ROOT_SUBVOL, inum: into_parent },
+                name.as_ptr(), name.len() as u32, snapshot_src,
+                &mut subvol, &mut local_root)
+        })?;
+
+        let ret = (|| -> Result<(), bch_errcode> {
+            let mut r = Receiver { fs: self, subvol, inums: HashMap::new() };
+            if snapshot_src != 0 {
+                r.load_inums()?;
+            }
+            r.inums.insert(root, local_root);
+            r.set_received_inum(local_root, root)?;
+
+            r.apply_dirents(&inodes, links, unlinks)?;
+
+            for x in xattrs {
+                if let Some(inum) = r.local(x.inum) {
+                    r.xattr_set(inum, x.x_type, &x.name, x.value.as_deref())?;
+                }
+            }
+
+            while rec.0 == SendRecord::Data {
+                let mut p = Payload { b: &rec.1 };
+                let inum    = p.u64()?;
+                let offset  = p.u64()?;
+
+                if let Some(inum) = r.local(inum) {
+                    self.write_file(subvol, inum.inum, offset, p.rest())?;
+                }
+
+                rec = read_record(stream)?;
+            }
+
+            if rec.0 != SendRecord::End {
+                return Err(INVALID);
+            }
+
+            /* After the data, so that file sizes come out right: */
+            for (inum, u) in &inodes {
+                if let Some(inum) = r.local(*inum) {
+                    errcode_to_result(unsafe { c::bch2_file_setattr(self.raw, inum, u) })?;
+                }
+            }
+
+            for inum in destroys {
+                if let Some(inum) = r.local(inum) {
+                    errcode_to_result(unsafe { c::bch2_inode_rm(self.raw, inum) })?;
+                }
+            }
+
+            r.xattr_set(c::subvol_inum { subvol, inum: local_root },
+                KEY_TYPE_XATTR_INDEX_TRUSTED, RECEIVED_SNAPSHOT, Some(&snapshot.to_le_bytes()))
+        })();
+
+        if let Err(e) = ret {
+            /* Don't leave a partially received subvolume behind: */
+            let _ = unsafe {
+                c::bch2_file_delete_subvol(self.raw,
+                    c::subvol_inum { subvol: BCACHEFS_ROOT_SUBVOL, inum: into_parent },
+                    name.as_ptr(), name.len() as u32)
+            };
+            return Err(e);
+        }
+
+        Ok(subvol)
+    }
+}
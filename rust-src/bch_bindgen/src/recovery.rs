@@ -0,0 +1,78 @@
+use crate::c;
+use crate::errcode::{bch_errcode, errcode_to_result};
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+pub use crate::c::bch_recovery_pass as RecoveryPass;
+
+impl RecoveryPass {
+    pub fn name(self) -> &'static str {
+        unsafe { CStr::from_ptr(*c::bch2_recovery_passes.as_ptr().add(self as usize)) }
+            .to_str().unwrap()
+    }
+}
+
+/* bch2_recovery_passes is NULL terminated: */
+fn nr_recovery_passes() -> u64 {
+    let mut nr = 0;
+
+    while !unsafe { *c::bch2_recovery_passes.as_ptr().add(nr) }.is_null() {
+        nr += 1;
+    }
+    nr as u64
+}
+
+const PROGRESS_POLL: Duration = Duration::from_millis(10);
+
+struct FsPtr(*mut c::bch_fs);
+
+/* Only c->curr_recovery_pass is read through it, while the fs is starting: */
+unsafe impl Send for FsPtr {}
+
+/// Start a filesystem opened with nostart, calling `progress(pass, done, total)`
+/// from another thread whenever the recovery pass being run changes. Passes
+/// are sampled while recovery runs; completed passes too short to have been
+/// seen are reported, in order, once it finishes.
+pub(crate) fn start_with_progress(c: *mut c::bch_fs,
+                                  progress: &mut (dyn FnMut(RecoveryPass, u64, u64) + Send))
+                                  -> Result<(), bch_errcode> {
+    let nr = nr_recovery_passes();
+    let done = &AtomicBool::new(false);
+    let fs = FsPtr(c);
+    let poll_progress = &mut *progress;
+
+    let (ret, last) = std::thread::scope(|s| {
+        let poll = s.spawn(move || {
+            let mut last = None;
+
+            while !done.load(Ordering::Acquire) {
+                /* Read as an integer: it's one past the last pass when recovery finishes */
+                let pass = unsafe {
+                    std::ptr::read_volatile(std::ptr::addr_of!((*fs.0).curr_recovery_pass) as *const u32)
+                } as u64;
+
+                if pass < nr && last != Some(pass) {
+                    poll_progress(unsafe { std::mem::transmute(pass as u32) }, pass, nr);
+                    last = Some(pass);
+                }
+                std::thread::sleep(PROGRESS_POLL);
+            }
+            last
+        });
+
+        /* Recovery runs here, not in the polling thread - it needs current: */
+        let ret = errcode_to_result(unsafe { c::bch2_fs_start(c) });
+        done.store(true, Ordering::Release);
+        (ret, poll.join().unwrap())
+    });
+    ret?;
+
+    let complete = unsafe { (*c).recovery_passes_complete };
+    for pass in last.map_or(0, |l| l + 1)..nr {
+        if complete & (1 << pass) != 0 {
+            progress(unsafe { std::mem::transmute(pass as u32) }, pass, nr);
+        }
+    }
+    Ok(())
+}
@@ -16,8 +16,7 @@ pub fn read_super_opts(
         unsafe { crate::bcachefs::bch2_read_super(path.as_ptr(), &mut opts, sb.as_mut_ptr()) };
 
     if ret != 0 {
-        let err: bch_errcode = unsafe { ::std::mem::transmute(ret) };
-        Err(anyhow!(err))
+        Err(anyhow!(bch_errcode::from_raw(-ret)))
     } else {
         Ok(unsafe { sb.assume_init() })
     }
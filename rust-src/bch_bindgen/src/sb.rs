@@ -0,0 +1,178 @@
+use crate::c;
+use crate::errcode::{bch_errcode, errcode_to_result};
+use crate::fs::Fs;
+use bitflags::bitflags;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+bitflags! {
+    /// Incompatible features: a filesystem using a feature we don't know
+    /// about can't safely be used
+    pub struct Features: u64 {
+        const LZ4                           = 1 << c::bch_sb_feature::BCH_FEATURE_lz4 as u64;
+        const GZIP                          = 1 << c::bch_sb_feature::BCH_FEATURE_gzip as u64;
+        const ZSTD                          = 1 << c::bch_sb_feature::BCH_FEATURE_zstd as u64;
+        const ATOMIC_NLINK                  = 1 << c::bch_sb_feature::BCH_FEATURE_atomic_nlink as u64;
+        const EC                            = 1 << c::bch_sb_feature::BCH_FEATURE_ec as u64;
+        const JOURNAL_SEQ_BLACKLIST_V3      = 1 << c::bch_sb_feature::BCH_FEATURE_journal_seq_blacklist_v3 as u64;
+        const REFLINK                       = 1 << c::bch_sb_feature::BCH_FEATURE_reflink as u64;
+        const NEW_SIPHASH                   = 1 << c::bch_sb_feature::BCH_FEATURE_new_siphash as u64;
+        const INLINE_DATA                   = 1 << c::bch_sb_feature::BCH_FEATURE_inline_data as u64;
+        const NEW_EXTENT_OVERWRITE          = 1 << c::bch_sb_feature::BCH_FEATURE_new_extent_overwrite as u64;
+        const INCOMPRESSIBLE                = 1 << c::bch_sb_feature::BCH_FEATURE_incompressible as u64;
+        const BTREE_PTR_V2                  = 1 << c::bch_sb_feature::BCH_FEATURE_btree_ptr_v2 as u64;
+        const EXTENTS_ABOVE_BTREE_UPDATES   = 1 << c::bch_sb_feature::BCH_FEATURE_extents_above_btree_updates as u64;
+        const BTREE_UPDATES_JOURNALLED      = 1 << c::bch_sb_feature::BCH_FEATURE_btree_updates_journalled as u64;
+        const REFLINK_INLINE_DATA           = 1 << c::bch_sb_feature::BCH_FEATURE_reflink_inline_data as u64;
+        const NEW_VARINT                    = 1 << c::bch_sb_feature::BCH_FEATURE_new_varint as u64;
+        const JOURNAL_NO_FLUSH              = 1 << c::bch_sb_feature::BCH_FEATURE_journal_no_flush as u64;
+        const ALLOC_V2                      = 1 << c::bch_sb_feature::BCH_FEATURE_alloc_v2 as u64;
+        const EXTENTS_ACROSS_BTREE_NODES    = 1 << c::bch_sb_feature::BCH_FEATURE_extents_across_btree_nodes as u64;
+    }
+}
+
+bitflags! {
+    /// Compatible features: safe to ignore if unknown
+    pub struct CompatFeatures: u64 {
+        const ALLOC_INFO                        = 1 << c::bch_sb_compat::BCH_COMPAT_alloc_info as u64;
+        const ALLOC_METADATA                    = 1 << c::bch_sb_compat::BCH_COMPAT_alloc_metadata as u64;
+        const EXTENTS_ABOVE_BTREE_UPDATES_DONE  = 1 << c::bch_sb_compat::BCH_COMPAT_extents_above_btree_updates_done as u64;
+        const BFORMAT_OVERFLOW_DONE             = 1 << c::bch_sb_compat::BCH_COMPAT_bformat_overflow_done as u64;
+    }
+}
+
+/// Decoded view of a superblock
+pub struct SuperInfo<'a> {
+    pub sb: &'a c::bch_sb,
+}
+
+impl<'a> SuperInfo<'a> {
+    pub fn new(sb: &'a c::bch_sb) -> SuperInfo<'a> {
+        SuperInfo { sb }
+    }
+
+    /// (compat, incompat) feature flags; bits this build doesn't know are
+    /// dropped, see is_supported()
+    pub fn features(&self) -> (CompatFeatures, Features) {
+        (CompatFeatures::from_bits_truncate(u64::from_le(self.sb.compat[0])),
+         Features::from_bits_truncate(u64::from_le(self.sb.features[0])))
+    }
+
+    /// Whether every incompatible feature in use is understood by the linked
+    /// libbcachefs
+    pub fn is_supported(&self) -> bool {
+        Features::from_bits(u64::from_le(self.sb.features[0])).is_some() &&
+            self.sb.features[1] == 0
+    }
+
+    /// Filesystem label; None if unset
+    pub fn label(&self) -> Option<String> {
+        let label = &self.sb.label;
+        let len = label.iter().position(|&b| b == 0).unwrap_or(label.len());
+
+        (len != 0).then(|| String::from_utf8_lossy(&label[..len]).into_owned())
+    }
+
+    /// When the filesystem was formatted; None for filesystems formatted
+    /// before this was recorded
+    pub fn created_at(&self) -> Option<SystemTime> {
+        let ns = u64::from_le(self.sb.time_base_lo);
+
+        (ns != 0).then(|| UNIX_EPOCH + Duration::from_nanos(ns))
+    }
+
+    /// The most recent mount time recorded by any member device
+    pub fn last_mount_at(&self) -> Option<SystemTime> {
+        let sb = self.sb as *const c::bch_sb as *mut c::bch_sb;
+
+        (0..self.sb.nr_devices as i32)
+            .map(|i| unsafe { c::bch2_sb_member_get(sb, i) })
+            .filter(|m| m.uuid.b.iter().any(|&b| b != 0))
+            .map(|m| u64::from_le(m.last_mount))
+            .filter(|&t| t != 0)
+            .max()
+            .map(|t| UNIX_EPOCH + Duration::from_secs(t))
+    }
+
+    /// None if the filesystem isn't encrypted
+    pub fn encryption(&self) -> Option<EncryptionInfo> {
+        let flags = c::bch_sb_flags1(u64::from_le(self.sb.flags[1]));
+
+        if flags.BCH_SB_ENCRYPTION_TYPE() == 0 {
+            return None;
+        }
+        let crypt = self.sb.crypt()?;
+
+        let algo = if flags.BCH_SB_128_BIT_MACS() {
+            c::bch_csum_type::BCH_CSUM_chacha20_poly1305_128
+        } else {
+            c::bch_csum_type::BCH_CSUM_chacha20_poly1305_80
+        };
+        let key_derivation = crypt.scrypt_flags().map(|f| KeyDerivation::Scrypt {
+            n_log2: f.N() as u32,
+            r_log2: f.R() as u32,
+            p_log2: f.P() as u32,
+        });
+
+        Some(EncryptionInfo { algo, key_derivation })
+    }
+}
+
+/// Key derivation function used to turn the passphrase into the key that
+/// encrypts the filesystem's master key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyDerivation {
+    /// Parameters are stored as base 2 logs
+    Scrypt { n_log2: u32, r_log2: u32, p_log2: u32 },
+}
+
+/// How a filesystem is encrypted, see [`SuperInfo::encryption`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncryptionInfo {
+    /// Used for data, and implies its MAC size: ChaCha20/Poly1305 with a 256
+    /// bit key, and 80 or 128 bit MACs. Metadata always uses 128 bit MACs.
+    pub algo:           c::bch_csum_type,
+    /// None if the key derivation function is one this build doesn't know
+    pub key_derivation: Option<KeyDerivation>,
+}
+
+impl Fs {
+    pub fn super_info(&self) -> SuperInfo {
+        SuperInfo::new(unsafe { (*self.raw).disk_sb.sb() })
+    }
+
+    /// Modify the superblock with sb_lock held, then write it out
+    pub fn sb_update<R, F>(&self, f: F) -> Result<R, bch_errcode>
+        where F: FnOnce(&mut c::bch_sb) -> R {
+        unsafe {
+            let lock = &mut (*self.raw).sb_lock.lock as *mut _ as *mut libc::pthread_mutex_t;
+
+            libc::pthread_mutex_lock(lock);
+            let r = f(&mut *(*self.raw).disk_sb.sb);
+            let ret = errcode_to_result(c::bch2_write_super(self.raw));
+            libc::pthread_mutex_unlock(lock);
+
+            ret.map(|_| r)
+        }
+    }
+
+    pub fn label(&self) -> Option<String> {
+        self.super_info().label()
+    }
+
+    pub fn encryption(&self) -> Option<EncryptionInfo> {
+        self.super_info().encryption()
+    }
+
+    pub fn set_label(&self, label: &str) -> Result<(), bch_errcode> {
+        let label = label.as_bytes();
+
+        if label.len() > c::BCH_SB_LABEL_SIZE as usize {
+            return Err(bch_errcode::BCH_ERR_ERANGE_option_too_big);
+        }
+
+        self.sb_update(|sb| {
+            sb.label = [0; c::BCH_SB_LABEL_SIZE as usize];
+            sb.label[..label.len()].copy_from_slice(label);
+        })
+    }
+}
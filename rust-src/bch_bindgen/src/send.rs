@@ -0,0 +1,370 @@
+use crate::c;
+use crate::bkey::BkeySC;
+use crate::btree::{BtreeTrans, BtreeIterFlags};
+use crate::dirent::DirentTarget;
+use crate::errcode::{bch_errcode, io_err_to_errcode};
+use crate::fs::Fs;
+use crate::io::READ_CHUNK;
+use crate::xattr::KEY_TYPE_XATTR_INDEX_TRUSTED;
+use crate::{spos, SPOS_MAX};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::ops::ControlFlow;
+
+/*
+ * Send streams: the inodes, xattrs, dirents and file data of a subvolume, or
+ * just what changed since an older snapshot, serialized so they can be applied
+ * to another filesystem.
+ *
+ * Everything is little endian. The stream starts with a header:
+ *
+ *   magic (8 bytes), version (u32), snapshot sent (u32), parent snapshot
+ *   (u32, 0 for a full stream), inode number of the subvolume root (u64)
+ *
+ * followed by records:
+ *
+ *   type (u8), payload length (u32, at most MAX_RECORD), payload
+ *
+ * Records are emitted in the order inodes, xattrs, dirents, data, and the
+ * stream is terminated by an End record. Names and values are the remainder
+ * of the payload unless noted otherwise; times are in nanoseconds since the
+ * epoch.
+ */
+
+pub const SEND_MAGIC: [u8; 8] = *b"BCHSEND\0";
+pub const SEND_VERSION: u32 = 1;
+
+/* Data records carry up to READ_CHUNK bytes, everything else is smaller: */
+pub(crate) const MAX_RECORD: usize = 16 + READ_CHUNK;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SendRecord {
+    /// Create or update an inode: inum (u64), mode, uid, gid, nlink, dev
+    /// (u32 each), size (u64), atime, mtime, ctime, otime (i64 each)
+    Inode       = 1,
+    /// inum (u64)
+    Destroy     = 2,
+    /// Create or replace a dirent: dir (u64), target inum (u64), d_type (u8),
+    /// name
+    Link        = 3,
+    /// dir (u64), name
+    Unlink      = 4,
+    /// Create or replace an xattr: inum (u64), type (u8), name length (u8),
+    /// name, value
+    XattrSet    = 5,
+    /// inum (u64), type (u8), name
+    XattrRemove = 6,
+    /// File data: inum (u64), offset in bytes (u64), data
+    Data        = 7,
+    End         = 8,
+}
+
+impl SendRecord {
+    pub(crate) fn from_u8(v: u8) -> Option<SendRecord> {
+        use SendRecord::*;
+
+        [Inode, Destroy, Link, Unlink, XattrSet, XattrRemove, Data, End]
+            .iter()
+            .copied()
+            .find(|r| *r as u8 == v)
+    }
+}
+
+/*
+ * Bookkeeping for incremental receives, kept as trusted xattrs: the sender's
+ * inode number on each received inode, and the snapshot received on the root.
+ * These aren't sent.
+ */
+pub(crate) const RECEIVED_INUM: &[u8]        = b"bcachefs_received_inum";
+pub(crate) const RECEIVED_SNAPSHOT: &[u8]    = b"bcachefs_received_snapshot";
+
+pub(crate) fn is_receive_xattr(x_type: u8, name: &[u8]) -> bool {
+    x_type == KEY_TYPE_XATTR_INDEX_TRUSTED &&
+        (name == RECEIVED_INUM || name == RECEIVED_SNAPSHOT)
+}
+
+/*
+ * A key that hasn't been overwritten since a snapshot was taken is the same
+ * key when seen from both the snapshot and the subvolume, so comparing
+ * position, snapshot and version is enough to tell what changed:
+ */
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct KeyId {
+    snapshot:   u32,
+    version_hi: u32,
+    version_lo: u64,
+    size:       u32,
+    type_:      u8,
+}
+
+impl KeyId {
+    fn new(k: &c::bkey) -> KeyId {
+        let version = k.version;
+
+        KeyId {
+            snapshot:   k.p.snapshot,
+            version_hi: version.hi,
+            version_lo: version.lo,
+            size:       k.size,
+            type_:      k.type_,
+        }
+    }
+}
+
+/// The keys of a btree visible in a given snapshot, by inode and offset
+type View<T> = BTreeMap<(u64, u64), (KeyId, T)>;
+
+fn snapshot_view<T, F>(trans: &BtreeTrans, btree: c::btree_id, snapshot: u32, mut f: F) -> Result<View<T>, bch_errcode>
+    where F: FnMut(&BkeySC) -> Option<T> {
+    let mut ret = BTreeMap::new();
+
+    trans.for_each_key(btree, spos(0, 0, snapshot), SPOS_MAX,
+        BtreeIterFlags::PREFETCH|BtreeIterFlags::FILTER_SNAPSHOTS,
+        |k| {
+            if let Some(v) = f(&k) {
+                ret.insert((k.k.p.inode, k.k.p.offset), (KeyId::new(k.k), v));
+            }
+            ControlFlow::Continue(())
+        })?;
+
+    Ok(ret)
+}
+
+/// Entries of `new` that aren't the same key in `old`, and entries of `old`
+/// that have been removed - either their slot is now empty or, per `same`, it
+/// holds a different entry
+fn view_diff<'a, T>(new: &'a View<T>, old: Option<&'a View<T>>, same: impl Fn(&T, &T) -> bool)
+    -> (Vec<&'a T>, Vec<&'a T>) {
+    let old = match old {
+        Some(old)   => old,
+        None        => return (new.values().map(|(_, v)| v).collect(), Vec::new()),
+    };
+
+    let changed = new.iter()
+        .filter(|(pos, (id, _))| old.get(pos).map_or(true, |(old_id, _)| old_id != id))
+        .map(|(_, (_, v))| v)
+        .collect();
+
+    let removed = old.iter()
+        .filter(|(pos, (_, v))| new.get(pos).map_or(true, |(_, new_v)| !same(v, new_v)))
+        .map(|(_, (_, v))| v)
+        .collect();
+
+    (changed, removed)
+}
+
+struct SendDirent {
+    dir:    u64,
+    target: u64,
+    d_type: u8,
+    name:   Vec<u8>,
+}
+
+struct SendXattr {
+    inum:   u64,
+    x_type: u8,
+    name:   Vec<u8>,
+    value:  Vec<u8>,
+}
+
+struct StreamWriter<'w, W: Write> {
+    out:    &'w mut W,
+}
+
+impl<'w, W: Write> StreamWriter<'w, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), bch_errcode> {
+        self.out.write_all(buf).map_err(io_err_to_errcode)
+    }
+
+    fn record(&mut self, ty: SendRecord, payload: &[u8]) -> Result<(), bch_errcode> {
+        self.write(&[ty as u8])?;
+        self.write(&(payload.len() as u32).to_le_bytes())?;
+        self.write(payload)
+    }
+}
+
+fn inode_payload(fs: &Fs, u: &c::bch_inode_unpacked) -> Vec<u8> {
+    let mut p = Vec::new();
+
+    p.extend_from_slice(&u.bi_inum.to_le_bytes());
+    p.extend_from_slice(&(u.bi_mode as u32).to_le_bytes());
+    p.extend_from_slice(&u.bi_uid.to_le_bytes());
+    p.extend_from_slice(&u.bi_gid.to_le_bytes());
+    p.extend_from_slice(&u.bi_nlink.to_le_bytes());
+    p.extend_from_slice(&u.bi_dev.to_le_bytes());
+    p.extend_from_slice(&u.bi_size.to_le_bytes());
+    for t in [u.bi_atime, u.bi_mtime, u.bi_ctime, u.bi_otime] {
+        p.extend_from_slice(&fs.time_to_ns(t).to_le_bytes());
+    }
+    p
+}
+
+/// Sector ranges, by inode, of extents in `view` that aren't the same key in
+/// `other`
+fn changed_ranges(view: &View<u64>, other: Option<&View<u64>>, ranges: &mut BTreeMap<u64, Vec<(u64, u64)>>) {
+    for (&(inum, end), (id, &start)) in view {
+        if other.and_then(|o| o.get(&(inum, end))).map_or(false, |(o_id, _)| o_id == id) {
+            continue;
+        }
+        ranges.entry(inum).or_default().push((start, end));
+    }
+}
+
+/// Merge overlapping and adjacent ranges
+fn merge_ranges(mut r: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    let mut ret: Vec<(u64, u64)> = Vec::new();
+
+    r.sort();
+    for (start, end) in r {
+        match ret.last_mut() {
+            Some(last) if start <= last.1 => last.1 = std::cmp::max(last.1, end),
+            _ => ret.push((start, end)),
+        }
+    }
+    ret
+}
+
+impl Fs {
+    /// Write a send stream for subvolume `subvol` to `out`: everything in the
+    /// subvolume, or with `parent_snap`, only what differs from that snapshot
+    /// (which should be an older snapshot of the same subvolume).
+    ///
+    /// Changed file data is sent at extent granularity; holes in a full
+    /// stream aren't sent. Nested subvolumes aren't included.
+    pub fn send<W: Write>(&self, subvol: u32, parent_snap: Option<u32>, out: &mut W) -> Result<(), bch_errcode> {
+        use c::btree_id::*;
+
+        /* Collect everything up front; read_file() needs its own transaction: */
+        let (snapshot, root_inum, new_inodes, new_xattrs, new_dirents, new_extents, old) = {
+            let trans = BtreeTrans::new(self);
+            let (snapshot, root_inum) = trans.lockrestart_do(|| {
+                let s = trans.subvolume_get(subvol)?;
+                Ok((u32::from_le(s.snapshot), u64::from_le(s.inode)))
+            })?;
+
+            let inodes = |snapshot| snapshot_view(&trans, BTREE_ID_inodes, snapshot,
+                |k| k.as_inode().ok().map(|i| i.u));
+            let xattrs = |snapshot| snapshot_view(&trans, BTREE_ID_xattrs, snapshot,
+                |k| k.as_xattr().ok()
+                    .filter(|x| !is_receive_xattr(x.x_type, x.name))
+                    .map(|x| SendXattr {
+                        inum:   x.inum,
+                        x_type: x.x_type,
+                        name:   x.name.to_vec(),
+                        value:  x.value.to_vec(),
+                    }));
+            let dirents = |snapshot| snapshot_view(&trans, BTREE_ID_dirents, snapshot,
+                |k| k.as_dirent().ok().and_then(|d| match d.target {
+                    DirentTarget::Inum(target) => Some(SendDirent {
+                        dir:    d.dir,
+                        target,
+                        d_type: d.d_type,
+                        name:   d.name.to_vec(),
+                    }),
+                    DirentTarget::Subvol { .. } => None,
+                }));
+            let extents = |snapshot| snapshot_view(&trans, BTREE_ID_extents, snapshot,
+                |k| Some(k.k.p.offset - k.k.size as u64));
+
+            let old = match parent_snap {
+                Some(p) => Some((inodes(p)?, xattrs(p)?, dirents(p)?, extents(p)?)),
+                None    => None,
+            };
+
+            (snapshot, root_inum, inodes(snapshot)?, xattrs(snapshot)?, dirents(snapshot)?, extents(snapshot)?, old)
+        };
+        let (old_inodes, old_xattrs, old_dirents, old_extents) = match &old {
+            Some((i, x, d, e))  => (Some(i), Some(x), Some(d), Some(e)),
+            None                => (None, None, None, None),
+        };
+
+        let mut w = StreamWriter { out };
+
+        w.write(&SEND_MAGIC)?;
+        w.write(&SEND_VERSION.to_le_bytes())?;
+        w.write(&snapshot.to_le_bytes())?;
+        w.write(&parent_snap.unwrap_or(0).to_le_bytes())?;
+        w.write(&root_inum.to_le_bytes())?;
+
+        let (changed, removed) = view_diff(&new_inodes, old_inodes, |_, _| true);
+        for u in removed {
+            w.record(SendRecord::Destroy, &u.bi_inum.to_le_bytes())?;
+        }
+        for u in changed {
+            w.record(SendRecord::Inode, &inode_payload(self, u))?;
+        }
+
+        let (changed, removed) = view_diff(&new_xattrs, old_xattrs,
+            |a, b| a.x_type == b.x_type && a.name == b.name);
+        for x in removed {
+            let mut p = x.inum.to_le_bytes().to_vec();
+            p.push(x.x_type);
+            p.extend_from_slice(&x.name);
+            w.record(SendRecord::XattrRemove, &p)?;
+        }
+        for x in changed {
+            let mut p = x.inum.to_le_bytes().to_vec();
+            p.push(x.x_type);
+            p.push(x.name.len() as u8);
+            p.extend_from_slice(&x.name);
+            p.extend_from_slice(&x.value);
+            w.record(SendRecord::XattrSet, &p)?;
+        }
+
+        let (changed, removed) = view_diff(&new_dirents, old_dirents,
+            |a, b| a.name == b.name);
+        for d in removed {
+            let mut p = d.dir.to_le_bytes().to_vec();
+            p.extend_from_slice(&d.name);
+            w.record(SendRecord::Unlink, &p)?;
+        }
+        for d in changed {
+            let mut p = d.dir.to_le_bytes().to_vec();
+            p.extend_from_slice(&d.target.to_le_bytes());
+            p.push(d.d_type);
+            p.extend_from_slice(&d.name);
+            w.record(SendRecord::Link, &p)?;
+        }
+
+        /*
+         * Data: resend every range covered by an extent that was added or
+         * overwritten, or that was removed (and now reads as zeroes):
+         */
+        let mut ranges = BTreeMap::new();
+        changed_ranges(&new_extents, old_extents, &mut ranges);
+        if let Some(old_extents) = old_extents {
+            changed_ranges(old_extents, Some(&new_extents), &mut ranges);
+        }
+
+        let mut buf = vec![0; READ_CHUNK];
+        for (inum, r) in ranges {
+            let size = match new_inodes.get(&(0, inum)) {
+                Some((_, u)) => u.bi_size,
+                None => continue,
+            };
+
+            for (start, end) in merge_ranges(r) {
+                let mut offset = start << 9;
+                let end = std::cmp::min(end << 9, size);
+
+                while offset < end {
+                    let len = std::cmp::min((end - offset) as usize, READ_CHUNK);
+                    let n = self.read_file(subvol, inum, offset, &mut buf[..len])?;
+                    if n == 0 {
+                        break;
+                    }
+
+                    let mut p = inum.to_le_bytes().to_vec();
+                    p.extend_from_slice(&offset.to_le_bytes());
+                    p.extend_from_slice(&buf[..n]);
+                    w.record(SendRecord::Data, &p)?;
+                    offset += n as u64;
+                }
+            }
+        }
+
+        w.record(SendRecord::End, &[])?;
+        w.out.flush().map_err(io_err_to_errcode)
+    }
+}
@@ -0,0 +1,289 @@
+use crate::c;
+use crate::bkey::{BkeySC, BkeyValC};
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::dirent::DirentTarget;
+use crate::errcode::{bch_errcode, errcode_to_result};
+use crate::fs::Fs;
+use crate::{spos, POS_MIN, SPOS_MAX};
+use std::collections::{BTreeSet, HashMap};
+use std::ops::ControlFlow;
+
+pub const BCACHEFS_ROOT_SUBVOL: u32 = 1;
+
+impl<'f> BtreeTrans<'f> {
+    pub fn subvolume_get(&self, subvol: u32) -> Result<c::bch_subvolume, bch_errcode> {
+        let mut s: c::bch_subvolume = Default::default();
+
+        errcode_to_result(unsafe { c::bch2_subvolume_get(self.raw, subvol, false, 0, &mut s) })?;
+        Ok(s)
+    }
+
+    /// The snapshot ID for the current version of subvolume `subvol`
+    pub fn subvol_snapshot(&self, subvol: u32) -> Result<u32, bch_errcode> {
+        self.subvolume_get(subvol).map(|s| u32::from_le(s.snapshot))
+    }
+
+    /// Inode numbers of the inodes visible in `snapshot`
+    fn snapshot_inodes(&self, snapshot: u32) -> Result<BTreeSet<u64>, bch_errcode> {
+        let mut ret = BTreeSet::new();
+
+        self.for_each_key(c::btree_id::BTREE_ID_inodes,
+            spos(0, 0, snapshot), spos(0, u64::MAX, snapshot),
+            BtreeIterFlags::FILTER_SNAPSHOTS,
+            |k| {
+                if k.inode_format().is_some() {
+                    ret.insert(k.k.p.offset);
+                }
+                ControlFlow::Continue(())
+            })?;
+        Ok(ret)
+    }
+}
+
+/// Space used by the file data in a snapshot: extents written in this
+/// snapshot are exclusive to it, extents it sees from ancestor snapshots are
+/// shared with the other snapshots descended from them
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SnapshotUsage {
+    pub id:                 u32,
+    /// Subvolume this is the current snapshot of
+    pub subvol:             u32,
+    pub exclusive_bytes:    u64,
+    pub shared_bytes:       u64,
+}
+
+impl Fs {
+    /// Usage of each snapshot that is the current version of a subvolume -
+    /// these are always leaf snapshots. This walks the extents btree once per
+    /// subvolume, counting logical (uncompressed, single replica) bytes.
+    pub fn snapshot_usage(&self) -> Result<Vec<SnapshotUsage>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut ret: Vec<SnapshotUsage> = Vec::new();
+
+        trans.for_each_key(c::btree_id::BTREE_ID_snapshots, POS_MIN, SPOS_MAX,
+            BtreeIterFlags::empty(),
+            |k| {
+                if let Ok(s) = k.as_snapshot() {
+                    if s.subvol != 0 && !s.deleted {
+                        ret.push(SnapshotUsage { id: s.id, subvol: s.subvol, ..Default::default() });
+                    }
+                }
+                ControlFlow::Continue(())
+            })?;
+
+        for u in &mut ret {
+            trans.for_each_key(c::btree_id::BTREE_ID_extents,
+                spos(0, 0, u.id), spos(u64::MAX, u64::MAX, u.id),
+                BtreeIterFlags::FILTER_SNAPSHOTS,
+                |k| {
+                    if k.is_extent_data() {
+                        let bytes = (k.k.size as u64) << 9;
+
+                        if k.k.p.snapshot == u.id {
+                            u.exclusive_bytes += bytes;
+                        } else {
+                            u.shared_bytes += bytes;
+                        }
+                    }
+                    ControlFlow::Continue(())
+                })?;
+        }
+
+        Ok(ret)
+    }
+
+    /// Snapshot IDs of snapshot subvolumes that `policy` doesn't keep. Advisory
+    /// only: nothing is deleted. Subvolumes that aren't snapshots, and ones
+    /// already unlinked, are never recommended.
+    pub fn recommend_prune(&self, policy: PrunePolicy) -> Result<Vec<u32>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        /* Snapshots by the subvolume they were taken of: */
+        let mut by_parent: HashMap<u32, Vec<(u32, i64)>> = HashMap::new();
+
+        trans.for_each_key(c::btree_id::BTREE_ID_subvolumes, POS_MIN, SPOS_MAX,
+            BtreeIterFlags::empty(),
+            |k| {
+                if let BkeyValC::subvolume(s) = k.v() {
+                    let flags = c::bch_subvolume_flags(u32::from_le(s.flags));
+
+                    if flags.SNAP() && !flags.UNLINKED() {
+                        by_parent.entry(u32::from_le(s.parent)).or_default()
+                            .push((u32::from_le(s.snapshot), self.time_to_ns(u64::from_le(s.otime.lo))));
+                    }
+                }
+                ControlFlow::Continue(())
+            })?;
+
+        Ok(by_parent.values_mut()
+            .flat_map(|snaps| {
+                snaps.sort_by(|a, b| b.1.cmp(&a.1));
+                prune_candidates(&policy, snaps)
+            })
+            .collect())
+    }
+
+    /// SHA-256 digest of subvolume `subvol`'s inodes, dirents, xattrs and
+    /// extents, as seen from the subvolume: each btree is hashed in key order,
+    /// then the per-btree digests are hashed together. Identical copies of a
+    /// subvolume - e.g. a snapshot that hasn't been modified - have the same
+    /// digest; file data isn't read, only the extent keys pointing to it.
+    pub fn subvol_metadata_digest(&self, subvol: u32) -> Result<[u8; 32], bch_errcode> {
+        use c::btree_id::*;
+
+        let trans = BtreeTrans::new(self);
+        let snapshot = trans.lockrestart_do(|| trans.subvol_snapshot(subvol))?;
+        let mut top = Sha256::new();
+
+        for btree in [BTREE_ID_inodes, BTREE_ID_dirents, BTREE_ID_xattrs, BTREE_ID_extents].iter().copied() {
+            let mut h = Sha256::new();
+            let mut err = Ok(());
+
+            trans.for_each_key(btree,
+                spos(0, 0, snapshot), spos(u64::MAX, u64::MAX, snapshot),
+                BtreeIterFlags::FILTER_SNAPSHOTS,
+                |k| {
+                    err = digest_key(&mut h, subvol, k);
+                    if err.is_ok() { ControlFlow::Continue(()) } else { ControlFlow::Break(()) }
+                })?;
+            err?;
+
+            top.update(&h.finish());
+        }
+
+        Ok(top.finish())
+    }
+
+    /// Inodes that exist in `snapshot` but not in its parent snapshot - those
+    /// created since the snapshot was taken - in inode number order. For a
+    /// snapshot with no parent that's every inode in it.
+    pub fn snapshot_added_inodes(&self, snapshot: u32) -> Result<Vec<u64>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let parent = trans.lockrestart_do(|| {
+            let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_snapshots,
+                spos(0, snapshot as u64, 0),
+                BtreeIterFlags::empty());
+
+            iter.peek_slot()?.as_snapshot().map(|s| s.parent)
+        })?;
+
+        let inodes = trans.snapshot_inodes(snapshot)?;
+        let parent_inodes = match parent {
+            0 => BTreeSet::new(),
+            p => trans.snapshot_inodes(p)?,
+        };
+
+        Ok(inodes.difference(&parent_inodes).copied().collect())
+    }
+}
+
+/// Which snapshots to keep, for [`Fs::recommend_prune`]: applied separately to
+/// the snapshots of each subvolume, newest first. A snapshot is kept if any
+/// rule keeps it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PrunePolicy {
+    /// Keep this many of the most recent snapshots
+    pub keep_last:      usize,
+    /// Keep the newest snapshot of each of this many most recent days that
+    /// have snapshots
+    pub keep_daily:     usize,
+    /// Likewise, for weeks
+    pub keep_weekly:    usize,
+}
+
+const NSEC_PER_DAY: i64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// (snapshot id, creation time in ns since the epoch) of one subvolume's
+/// snapshots, newest first; returns the ones no rule keeps
+fn prune_candidates(policy: &PrunePolicy, snaps: &[(u32, i64)]) -> Vec<u32> {
+    let mut keep = vec![false; snaps.len()];
+
+    for k in keep.iter_mut().take(policy.keep_last) {
+        *k = true;
+    }
+
+    for (period, nr) in [(NSEC_PER_DAY, policy.keep_daily), (NSEC_PER_DAY * 7, policy.keep_weekly)].iter().copied() {
+        let mut last_period = None;
+        let mut kept = 0;
+
+        for (i, &(_, time)) in snaps.iter().enumerate() {
+            if kept == nr {
+                break;
+            }
+
+            let p = time.div_euclid(period);
+            if last_period != Some(p) {
+                last_period = Some(p);
+                keep[i] = true;
+                kept += 1;
+            }
+        }
+    }
+
+    snaps.iter().zip(keep).filter(|(_, k)| !k).map(|(&(id, _), _)| id).collect()
+}
+
+struct Sha256(c::crypto_hash_sha256_state);
+
+impl Sha256 {
+    fn new() -> Sha256 {
+        let mut state: c::crypto_hash_sha256_state = Default::default();
+        unsafe { c::crypto_hash_sha256_init(&mut state) };
+        Sha256(state)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        unsafe { c::crypto_hash_sha256_update(&mut self.0, data.as_ptr(), data.len() as u64) };
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        unsafe { c::crypto_hash_sha256_final(&mut self.0, out.as_mut_ptr()) };
+        out
+    }
+}
+
+/*
+ * What goes into the digest for each key: only what's the same in an
+ * identical copy of the subvolume - so not snapshot IDs, subvolume IDs,
+ * journal sequence numbers and atimes in inodes, or where the subvolume's root
+ * is linked: a snapshot's root has its own dirent.
+ */
+fn digest_key(h: &mut Sha256, subvol: u32, k: BkeySC) -> Result<(), bch_errcode> {
+    h.update(&k.k.p.inode.to_le_bytes());
+    h.update(&k.k.p.offset.to_le_bytes());
+    h.update(&k.k.size.to_le_bytes());
+    h.update(&[k.k.type_]);
+
+    if k.inode_format().is_some() {
+        let u = k.as_inode()?.u;
+        let hash_seed = u.bi_hash_seed;
+        let (dir, dir_offset) = if u.bi_subvol != 0 { (0, 0) } else { (u.bi_dir, u.bi_dir_offset) };
+
+        for v in [u.bi_size, u.bi_sectors, u.bi_flags as u64, u.bi_mode as u64,
+                  u.bi_ctime as u64, u.bi_mtime as u64, u.bi_otime as u64,
+                  u.bi_uid as u64, u.bi_gid as u64, u.bi_nlink as u64,
+                  u.bi_generation as u64, u.bi_dev as u64,
+                  dir, dir_offset, hash_seed,
+                  u.bi_data_checksum as u64, u.bi_compression as u64, u.bi_project as u64,
+                  u.bi_background_compression as u64, u.bi_data_replicas as u64,
+                  u.bi_promote_target as u64, u.bi_foreground_target as u64,
+                  u.bi_background_target as u64, u.bi_erasure_code as u64,
+                  u.bi_fields_set as u64, u.bi_nocow as u64].iter() {
+            h.update(&v.to_le_bytes());
+        }
+    } else if let Ok(d) = k.as_dirent() {
+        if let DirentTarget::Subvol { parent, .. } = d.target {
+            if parent != subvol {
+                return Ok(());
+            }
+        } else if let DirentTarget::Inum(inum) = d.target {
+            h.update(&inum.to_le_bytes());
+        }
+        h.update(&[d.d_type]);
+        h.update(d.name);
+    } else {
+        h.update(k.val_bytes());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use crate::bkey::{BkeySC, BkeyValC};
+use crate::errcode::bch_errcode;
+use byteorder::{LittleEndian, ByteOrder};
+
+/* Not exported by bindgen: */
+pub const KEY_TYPE_XATTR_INDEX_TRUSTED: u8 = 3;
+
+/// A decoded xattr; name and value borrow from the key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Xattr<'a> {
+    pub inum:   u64,
+    /// KEY_TYPE_XATTR_INDEX_*
+    pub x_type: u8,
+    pub name:   &'a [u8],
+    pub value:  &'a [u8],
+}
+
+impl<'a> BkeySC<'a> {
+    pub fn as_xattr(&self) -> Result<Xattr<'a>, bch_errcode> {
+        if !matches!(self.v(), BkeyValC::xattr(_)) {
+            return Err(bch_errcode::BCH_ERR_ENOENT_bkey_type_mismatch);
+        }
+
+        /* x_type, name_len, val_len, then name and value: */
+        let b = self.val_bytes();
+        if b.len() < 4 {
+            return Err(bch_errcode::BCH_ERR_invalid_bkey);
+        }
+
+        let name_len = b[1] as usize;
+        let val_len = LittleEndian::read_u16(&b[2..]) as usize;
+        if b.len() < 4 + name_len + val_len {
+            return Err(bch_errcode::BCH_ERR_invalid_bkey);
+        }
+
+        let name = &b[4..4 + name_len];
+        let value = &b[4 + name_len..4 + name_len + val_len];
+
+        Ok(Xattr { inum: self.k.p.inode, x_type: b[0], name, value })
+    }
+}
@@ -0,0 +1,1636 @@
+use anyhow::anyhow;
+use atty::Stream;
+use bch_bindgen::{bcachefs, spos, POS_MIN, SPOS_MAX};
+use bch_bindgen::bkey::{BkeySC, OwnedBkey};
+use bch_bindgen::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use bch_bindgen::device::{Device, DeviceState};
+use bch_bindgen::errcode::errcode_to_result;
+use bch_bindgen::fs::{DeviceBackend, Fs, FsOpenOptions};
+use bch_bindgen::io::HashAlgo;
+use bch_bindgen::opt_set;
+use bch_bindgen::opts::{ErrorAction, TargetKind};
+use bch_bindgen::subvolume::PrunePolicy;
+use clap::{Parser, Subcommand};
+use log::{error, LevelFilter};
+use std::ffi::{c_int, c_char};
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::ops::ControlFlow;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::logger::SimpleLogger;
+use crate::transform_c_args;
+
+/* Deterministic file contents, so that tests can create identical files: */
+fn file_data(size: usize, seed: u8) -> Vec<u8> {
+    (0..size).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+}
+
+fn lookup_parent<'p>(fs: &Fs, path: &'p Path) -> anyhow::Result<(bcachefs::subvol_inum, &'p [u8])> {
+    let name = path.file_name()
+        .ok_or_else(|| anyhow!("{}: no file name", path.display()))?;
+    let dir = fs.lookup_path(path.parent().unwrap_or_else(|| Path::new("/")))?;
+
+    Ok((dir, name.as_bytes()))
+}
+
+fn create_inode(fs: &Fs, path: &Path, mode: u32) -> anyhow::Result<bcachefs::subvol_inum> {
+    let (dir, name) = lookup_parent(fs, path)?;
+    let mut inum = 0;
+
+    errcode_to_result(unsafe {
+        bcachefs::bch2_file_create(fs.raw, dir, name.as_ptr(), name.len() as u32, mode, 0, &mut inum)
+    })?;
+    println!("inum: {}", inum);
+
+    Ok(bcachefs::subvol_inum { subvol: dir.subvol, inum })
+}
+
+fn create(fs: &Fs, path: &Path, size: usize, offset: u64, seed: u8, dev: Option<u32>) -> anyhow::Result<()> {
+    let inum = create_inode(fs, path, libc::S_IFREG|0o644)?;
+    let data = file_data(size, seed);
+
+    match dev {
+        Some(dev)   => fs.write_file_on_device(dev, inum.subvol, inum.inum, offset, &data)?,
+        None        => fs.write_file(inum.subvol, inum.inum, offset, &data)?,
+    }
+    Ok(())
+}
+
+fn mknod(fs: &Fs, path: &Path, mode: u32) -> anyhow::Result<()> {
+    create_inode(fs, path, mode)?;
+    Ok(())
+}
+
+fn unlink(fs: &Fs, path: &Path) -> anyhow::Result<()> {
+    let (dir, name) = lookup_parent(fs, path)?;
+
+    errcode_to_result(unsafe {
+        bcachefs::bch2_file_unlink(fs.raw, dir, name.as_ptr(), name.len() as u32)
+    })?;
+    Ok(())
+}
+
+/* Hardlink `target` at `path`: */
+fn link(fs: &Fs, target: &Path, path: &Path) -> anyhow::Result<()> {
+    let target = fs.lookup_path(target)?;
+    let (dir, name) = lookup_parent(fs, path)?;
+
+    errcode_to_result(unsafe {
+        bcachefs::bch2_file_link(fs.raw, dir, name.as_ptr(), name.len() as u32, target.inum)
+    })?;
+    Ok(())
+}
+
+fn usage(fs: &Fs) -> anyhow::Result<()> {
+    let u = unsafe { bcachefs::bch2_fs_usage_read_short(fs.raw) };
+
+    println!("capacity: {}", u.capacity);
+    println!("used: {}", u.used);
+    println!("free: {}", u.free);
+    Ok(())
+}
+
+/* Create a subvolume at `path`, or a snapshot of subvolume `snapshot_src`: */
+fn create_subvol(fs: &Fs, path: &Path, snapshot_src: Option<&Path>) -> anyhow::Result<()> {
+    let src = match snapshot_src {
+        Some(src)   => fs.lookup_path(src)?.subvol,
+        None        => 0,
+    };
+    let (dir, name) = lookup_parent(fs, path)?;
+    let (mut subvol, mut inum) = (0, 0);
+
+    errcode_to_result(unsafe {
+        bcachefs::bch2_file_create_subvol(fs.raw, dir, name.as_ptr(), name.len() as u32,
+            src, &mut subvol, &mut inum)
+    })?;
+    println!("subvol: {}", subvol);
+    println!("inum: {}", inum);
+    Ok(())
+}
+
+fn snapshots(fs: &Fs) -> anyhow::Result<()> {
+    let trans = BtreeTrans::new(fs);
+
+    trans.for_each_key(bcachefs::btree_id::BTREE_ID_snapshots, POS_MIN, SPOS_MAX,
+        BtreeIterFlags::empty(),
+        |k| {
+            if let Ok(s) = k.as_snapshot() {
+                println!("snapshot: {} parent {} children {},{} subvol {}",
+                    s.id, s.parent, s.children[0], s.children[1], s.subvol);
+            }
+            ControlFlow::Continue(())
+        })?;
+    Ok(())
+}
+
+fn has_user_data(fs: &Fs) -> anyhow::Result<()> {
+    for dev in fs.devs() {
+        println!("user_data: {} {}", dev, fs.device_has_user_data(dev)?);
+    }
+    Ok(())
+}
+
+fn features(fs: &Fs) -> anyhow::Result<()> {
+    let sb = fs.super_info();
+    let (compat, incompat) = sb.features();
+
+    println!("compat: {:?}", compat);
+    println!("incompat: {:?}", incompat);
+    println!("supported: {}", sb.is_supported());
+    Ok(())
+}
+
+fn gc(fs: &Fs) -> anyhow::Result<()> {
+    let r = fs.run_gc()?;
+
+    println!("buckets_reclaimed: {}", r.buckets_reclaimed);
+    println!("sectors_reclaimed: {}", r.sectors_reclaimed);
+    Ok(())
+}
+
+fn accounting_sum(fs: &Fs) -> anyhow::Result<()> {
+    let trans = BtreeTrans::new(fs);
+    let mut extents_user = 0;
+
+    trans.for_each_key(bcachefs::btree_id::BTREE_ID_extents, POS_MIN, SPOS_MAX,
+        BtreeIterFlags::ALL_SNAPSHOTS,
+        |k| {
+            let a = k.accounting_contribution(fs);
+
+            if a.data_type == Some(bcachefs::bch_data_type::BCH_DATA_user) {
+                extents_user += a.dirty_sectors;
+            }
+            ControlFlow::Continue(())
+        })?;
+
+    let mut dev_user = 0;
+    for dev in fs.devs() {
+        dev_user += fs.dev_usage(dev)?.d[bcachefs::bch_data_type::BCH_DATA_user as usize].sectors;
+    }
+
+    println!("extents_user: {}", extents_user);
+    println!("dev_user: {}", dev_user);
+    Ok(())
+}
+
+fn delete_range(fs: &Fs, btree: bcachefs::btree_id, start: bcachefs::bpos, end: bcachefs::bpos,
+                no_triggers: bool) -> anyhow::Result<()> {
+    let flags = if no_triggers {
+        1 << bcachefs::btree_update_flags::__BTREE_TRIGGER_NORUN as u32
+    } else {
+        0
+    };
+
+    errcode_to_result(unsafe {
+        bcachefs::bch2_btree_delete_range(fs.raw, btree, start, end, flags, std::ptr::null_mut())
+    })?;
+    Ok(())
+}
+
+fn freespace(fs: &Fs) -> anyhow::Result<()> {
+    let trans = BtreeTrans::new(fs);
+    let mut free_buckets = 0;
+
+    trans.for_each_key(bcachefs::btree_id::BTREE_ID_freespace, POS_MIN, SPOS_MAX,
+        BtreeIterFlags::empty(),
+        |k| {
+            let (end, size) = (k.k.p, k.k.size as u64);
+            let start = bcachefs::bpos { offset: end.offset - size, ..end };
+
+            println!("freespace: {} {}", start, size);
+            free_buckets += size;
+            ControlFlow::Continue(())
+        })?;
+
+    println!("free_buckets: {}", free_buckets);
+    Ok(())
+}
+
+fn recalc_alloc(fs: &Fs) -> anyhow::Result<()> {
+    fs.recalc_alloc()?;
+    Ok(())
+}
+
+fn inode_type(fs: &Fs, path: &Path) -> anyhow::Result<()> {
+    let inum = fs.lookup_path(path)?;
+
+    println!("type: {:?}", fs.inode_type(inum.inum)?);
+    Ok(())
+}
+
+/* Visit keys until the first at or after `until`: */
+fn visit_keys(fs: &Fs, btree: bcachefs::btree_id, until: bcachefs::bpos) -> anyhow::Result<()> {
+    let trans = BtreeTrans::new(fs);
+
+    trans.for_each_key(btree, POS_MIN, SPOS_MAX, BtreeIterFlags::ALL_SNAPSHOTS, |k| {
+        let p = k.k.p;
+
+        println!("visit: {}", p);
+        if p >= until {
+            println!("found: {}", p);
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    })?;
+    Ok(())
+}
+
+fn print_opt<T: std::fmt::Display>(name: &str, v: Option<T>) {
+    match v {
+        Some(v) => println!("{}: {}", name, v),
+        None    => println!("{}: none", name),
+    }
+}
+
+fn inode(fs: &Fs, path: &Path) -> anyhow::Result<()> {
+    let inum = fs.lookup_path(path)?;
+    let inode = fs.inode_subvol(inum.subvol, inum.inum)?;
+
+    println!("inum: {}", inode.inum());
+    println!("format: {:?}", inode.format);
+    println!("size: {}", inode.u.bi_size);
+    print_opt("journal_seq", inode.journal_seq());
+    print_opt("version", inode.version());
+    print_opt("nocow", inode.nocow());
+    print_opt("compression", inode.options().compression);
+    Ok(())
+}
+
+/* Locks held after peeking at the start of a btree: */
+fn held_locks(fs: &Fs, btree: bcachefs::btree_id, intent: bool) -> anyhow::Result<()> {
+    let trans = BtreeTrans::new(fs);
+    let mut flags = BtreeIterFlags::ALL_SNAPSHOTS;
+
+    if intent {
+        flags |= BtreeIterFlags::INTENT;
+    }
+
+    let mut iter = BtreeIter::new(&trans, btree, POS_MIN, flags);
+    iter.peek_and_restart()?;
+
+    for l in trans.held_locks() {
+        println!("lock: {} {} {:?}{}", l.btree, l.level, l.lock_type,
+            if l.cached { " cached" } else { "" });
+    }
+    Ok(())
+}
+
+fn file_hash(fs: &Fs, path: &Path, algo: HashAlgo) -> anyhow::Result<()> {
+    let inum = fs.lookup_path(path)?;
+    let hash = fs.file_hash(inum.subvol, inum.inum, algo)?;
+
+    println!("hash: {}", hash.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    Ok(())
+}
+
+fn label(fs: &Fs, set: Option<String>) -> anyhow::Result<()> {
+    if let Some(l) = set {
+        fs.set_label(&l)?;
+    }
+    print_opt("label", fs.label());
+    Ok(())
+}
+
+fn subvol_snapshot(fs: &Fs, subvol: u32) -> anyhow::Result<u32> {
+    let trans = BtreeTrans::new(fs);
+
+    Ok(trans.lockrestart_do(|| trans.subvol_snapshot(subvol))?)
+}
+
+fn send(fs: &Fs, path: &Path, parent: Option<&Path>, out: &Path) -> anyhow::Result<()> {
+    let subvol = fs.lookup_path(path)?.subvol;
+    let parent_snap = match parent {
+        Some(p) => Some(subvol_snapshot(fs, fs.lookup_path(p)?.subvol)?),
+        None    => None,
+    };
+    let mut out = std::io::BufWriter::new(File::create(out)?);
+
+    fs.send(subvol, parent_snap, &mut out)?;
+    std::io::Write::flush(&mut out)?;
+
+    println!("snapshot: {}", subvol_snapshot(fs, subvol)?);
+    Ok(())
+}
+
+fn receive(fs: &Fs, stream: &Path, into: &Path) -> anyhow::Result<()> {
+    let into = fs.lookup_path(into)?;
+    let mut stream = std::io::BufReader::new(File::open(stream)?);
+
+    println!("subvol: {}", fs.receive(into.inum, &mut stream)?);
+    Ok(())
+}
+
+/* Print the position of each key between START and END, in either direction: */
+fn scan(fs: &Fs, btree: bcachefs::btree_id, start: bcachefs::bpos, end: bcachefs::bpos,
+        reverse: bool) -> anyhow::Result<()> {
+    let print = |k: BkeySC| {
+        println!("key: {} {}", k.k.p, k.k.size);
+        ControlFlow::Continue(())
+    };
+
+    if reverse {
+        fs.scan_reverse(btree, start, end, BtreeIterFlags::ALL_SNAPSHOTS, print)?;
+    } else {
+        BtreeTrans::new(fs).for_each_key(btree, start, end, BtreeIterFlags::ALL_SNAPSHOTS, print)?;
+    }
+    Ok(())
+}
+
+fn holes(fs: &Fs, path: &Path) -> anyhow::Result<()> {
+    let inum = fs.lookup_path(path)?;
+
+    for (start, len) in fs.holes(inum.inum)? {
+        println!("hole: {} {}", start, len);
+    }
+    Ok(())
+}
+
+fn set_compression(fs: &Fs, compression: &str, background: bool) -> anyhow::Result<()> {
+    fs.set_compression(compression, background)?;
+    Ok(())
+}
+
+fn dirent_count(fs: &Fs, path: &Path) -> anyhow::Result<()> {
+    let inum = fs.lookup_path(path)?;
+
+    println!("dirents: {}", fs.dirent_count(inum.inum)?);
+    Ok(())
+}
+
+fn rebalance(fs: &Fs, timeout: u64) -> anyhow::Result<()> {
+    println!("work: {}", fs.rebalance_work()?);
+
+    fs.rebalance_and_wait(std::time::Duration::from_secs(timeout), |done, total| {
+        println!("progress: {} {}", done, total);
+    })?;
+
+    println!("work: {}", fs.rebalance_work()?);
+    Ok(())
+}
+
+fn group_by_inode(fs: &Fs, btree: bcachefs::btree_id) -> anyhow::Result<()> {
+    fs.group_by_inode(btree, |inum, keys| {
+        let keys: Vec<_> = keys.iter().map(|k| k.k().p.to_string()).collect();
+
+        println!("group: {} {}", inum, keys.join(" "));
+    })?;
+    Ok(())
+}
+
+/* The checksums stored in a file's extents, without reading the data: */
+fn checksums(fs: &Fs, path: &Path) -> anyhow::Result<()> {
+    let inum = fs.lookup_path(path)?;
+    let trans = BtreeTrans::new(fs);
+    let snapshot = subvol_snapshot(fs, inum.subvol)?;
+
+    trans.for_each_key(bcachefs::btree_id::BTREE_ID_extents,
+        spos(inum.inum, 0, snapshot), spos(inum.inum, u64::MAX, snapshot),
+        BtreeIterFlags::FILTER_SNAPSHOTS,
+        |k| {
+            let start = (k.k.p.offset - k.k.size as u64) << 9;
+
+            for c in k.stored_checksums() {
+                println!("csum: {} dev {} type {} {:016x}{:016x}",
+                    start, c.dev, c.csum_type, c.csum.hi, c.csum.lo);
+            }
+            ControlFlow::Continue(())
+        })?;
+    Ok(())
+}
+
+fn same_inode(fs: &Fs, a: &Path, b: &Path) -> anyhow::Result<()> {
+    println!("same: {}", fs.same_inode(a, b)?);
+    Ok(())
+}
+
+fn scan_type(fs: &Fs, ty: bcachefs::bch_bkey_type) -> anyhow::Result<()> {
+    let mut nr = 0;
+
+    fs.scan_type(ty, |btree, k| {
+        println!("key: {} {}", btree, k.k.p);
+        nr += 1;
+    })?;
+    println!("keys: {}", nr);
+    Ok(())
+}
+
+fn read_flags(btree: bcachefs::btree_id) -> anyhow::Result<()> {
+    println!("flags: {:?}", BtreeIterFlags::for_read(btree));
+    Ok(())
+}
+
+fn times(fs: &Fs) -> anyhow::Result<()> {
+    let sb = fs.super_info();
+    let secs = |t: Option<std::time::SystemTime>| {
+        t.map(|t| t.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs())
+    };
+
+    print_opt("created_at", secs(sb.created_at()));
+    print_opt("last_mount_at", secs(sb.last_mount_at()));
+    Ok(())
+}
+
+fn btree_health(fs: &Fs, btree: bcachefs::btree_id) -> anyhow::Result<()> {
+    match fs.btree_health(btree)? {
+        Some(h) => {
+            println!("depth: {}", h.depth);
+            println!("fill: {:.6} {:.6} {:.6}", h.min_fill, h.avg_fill, h.max_fill);
+        }
+        None    => println!("health: none"),
+    }
+    Ok(())
+}
+
+fn rewrite_node(fs: &Fs, btree: bcachefs::btree_id, pos: bcachefs::bpos) -> anyhow::Result<()> {
+    fs.rewrite_btree_node(btree, pos)?;
+    Ok(())
+}
+
+fn snapshot_usage(fs: &Fs) -> anyhow::Result<()> {
+    for u in fs.snapshot_usage()? {
+        println!("usage: subvol {} snapshot {} exclusive {} shared {}",
+            u.subvol, u.id, u.exclusive_bytes, u.shared_bytes);
+    }
+    Ok(())
+}
+
+fn describe_key(k: Option<OwnedBkey>) -> String {
+    match k {
+        Some(k) => {
+            let k = k.as_bkey_sc();
+            let val: String = k.val_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+
+            format!("{} type {} {}", k.k.p, k.k.type_, val)
+        }
+        None    => "none".to_string(),
+    }
+}
+
+/* Look up a key normally, then twice via the key cache: */
+fn lookup(fs: &Fs, btree: bcachefs::btree_id, pos: bcachefs::bpos) -> anyhow::Result<()> {
+    let trans = BtreeTrans::new(fs);
+    let normal = trans.lockrestart_do(|| {
+        let mut iter = BtreeIter::new(&trans, btree, pos, BtreeIterFlags::empty());
+        let k = iter.peek_slot()?;
+
+        Ok((k.k.type_ != bcachefs::bch_bkey_type::KEY_TYPE_deleted as u8).then(|| k.to_owned_bkey()))
+    })?;
+
+    println!("key_cache_keys: {}", fs.key_cache_nr_keys());
+    println!("normal: {}", describe_key(normal));
+
+    for _ in 0..2 {
+        println!("cached: {}", describe_key(trans.lookup_cached(btree, pos)?));
+        println!("key_cache_keys: {}", fs.key_cache_nr_keys());
+    }
+    Ok(())
+}
+
+/* These don't open the filesystem: */
+fn is_bcachefs(devices: &[PathBuf]) -> anyhow::Result<()> {
+    for dev in devices {
+        println!("bcachefs: {} {}", dev.display(), Device::is_bcachefs(dev));
+    }
+    Ok(())
+}
+
+fn read_super(devices: &[PathBuf]) -> anyhow::Result<()> {
+    for dev in devices {
+        let sb = Device::read_super(dev)?;
+
+        println!("device: {} uuid {} dev_idx {} nr_devices {}",
+            dev.display(), sb.uuid, sb.dev_idx, sb.nr_devices);
+    }
+    Ok(())
+}
+
+fn fix_nlink(fs: &Fs, path: &Path) -> anyhow::Result<()> {
+    let inum = fs.lookup_path(path)?;
+    let (old, new) = fs.fix_nlink(inum.inum)?;
+
+    println!("nlink: {} {}", old, new);
+    Ok(())
+}
+
+/* Device usage from the alloc btree, next to the in-memory counters: */
+fn dev_accounting(fs: &Fs, dev: u32) -> anyhow::Result<()> {
+    let acct = fs.device_accounting(dev)?;
+    let usage = fs.dev_usage(dev)?;
+
+    for t in 0..acct.sectors.len() {
+        println!("buckets: {} {} {}", t, acct.buckets[t], usage.d[t].buckets);
+        println!("sectors: {} {} {}", t, acct.sectors[t], usage.d[t].sectors);
+    }
+    Ok(())
+}
+
+fn dev_state(fs: &Fs, dev: u32, set: Option<DeviceState>) -> anyhow::Result<()> {
+    if let Some(state) = set {
+        fs.set_device_state(dev, state)?;
+    }
+    println!("state: {}", device_state_str(fs.device_state(dev)?));
+    Ok(())
+}
+
+fn physical_map(fs: &Fs) -> anyhow::Result<()> {
+    for r in fs.physical_map()? {
+        println!("phys: dev {} offset {} len {} inode {} snapshot {}{}",
+            r.dev, r.offset, r.len, r.inode, r.snapshot,
+            if r.cached { " cached" } else { "" });
+    }
+    Ok(())
+}
+
+fn wait_free_space(fs: &Fs, sectors: u64, timeout: u64) -> anyhow::Result<()> {
+    fs.wait_for_free_space(sectors, std::time::Duration::from_millis(timeout))?;
+
+    println!("free: {}", unsafe { bcachefs::bch2_fs_usage_read_short(fs.raw) }.free);
+    Ok(())
+}
+
+/* Btree node cache contents, after peeking at the start of a btree: */
+fn cache_contents(fs: &Fs, btree: bcachefs::btree_id) -> anyhow::Result<()> {
+    let trans = BtreeTrans::new(fs);
+    let mut iter = BtreeIter::new(&trans, btree, POS_MIN, BtreeIterFlags::ALL_SNAPSHOTS);
+    iter.peek_and_restart()?;
+
+    println!("depth: {}", fs.btree_depth(btree));
+    for n in fs.btree_cache_contents() {
+        println!("node: {} {} {}{}", n.btree, n.level, n.pos,
+            if n.dirty { " dirty" } else { "" });
+    }
+    Ok(())
+}
+
+/* Reflink SECTORS of `src` from SRC_OFFSET to `dst` at DST_OFFSET, all of it by default: */
+fn reflink(fs: &Fs, src: &Path, dst: &Path, src_offset: u64, dst_offset: u64,
+           sectors: Option<u64>) -> anyhow::Result<()> {
+    let src = fs.lookup_path(src)?;
+    let dst = fs.lookup_path(dst)?;
+    let src_size = fs.inode_subvol(src.subvol, src.inum)?.u.bi_size;
+    let sectors = sectors.unwrap_or(((src_size + 511) >> 9).saturating_sub(src_offset));
+    let new_i_size = std::cmp::min((dst_offset + sectors) << 9,
+        (dst_offset << 9) + src_size.saturating_sub(src_offset << 9));
+
+    let done = fs.remap_range(dst.subvol, dst.inum, dst_offset, src.inum, src_offset,
+        sectors, new_i_size)?;
+    println!("sectors: {}", done);
+    Ok(())
+}
+
+fn reflink_ptrs(fs: &Fs, path: &Path) -> anyhow::Result<()> {
+    let inum = fs.lookup_path(path)?;
+    let trans = BtreeTrans::new(fs);
+    let snapshot = subvol_snapshot(fs, inum.subvol)?;
+
+    trans.for_each_key(bcachefs::btree_id::BTREE_ID_extents,
+        spos(inum.inum, 0, snapshot), spos(inum.inum, u64::MAX, snapshot),
+        BtreeIterFlags::FILTER_SNAPSHOTS,
+        |k| {
+            if let Ok(r) = k.as_reflink_p() {
+                let size = k.k.size as u64;
+
+                println!("reflink_p: {} {} idx {} pad {} {}",
+                    k.k.p.offset - size, size, r.idx, r.front_pad, r.back_pad);
+            }
+            ControlFlow::Continue(())
+        })?;
+    Ok(())
+}
+
+/* Print, and optionally set, options on the root of the subvolume at `path`: */
+fn subvol_options(fs: &Fs, path: &Path, set: Option<String>) -> anyhow::Result<()> {
+    let subvol = fs.lookup_path(path)?.subvol;
+
+    if let Some(s) = set {
+        let (name, value) = match s.split_once('=') {
+            Some((n, v))    => (n, Some(v)),
+            None            => (s.as_str(), None),
+        };
+        fs.set_subvol_option(subvol, name, value)?;
+    }
+
+    let opts = fs.subvol_options(subvol)?;
+    print_opt("compression", opts.compression);
+    print_opt("background_compression", opts.background_compression);
+    print_opt("data_replicas", opts.data_replicas);
+    Ok(())
+}
+
+fn fsck_estimate(fs: &Fs) -> anyhow::Result<()> {
+    println!("estimate_ns: {}", fs.estimate_fsck_time()?.as_nanos());
+    Ok(())
+}
+
+fn stripes(fs: &Fs) -> anyhow::Result<()> {
+    let mut ret = Ok(());
+
+    fs.scan_type(bcachefs::bch_bkey_type::KEY_TYPE_stripe, |_, k| {
+        match k.as_stripe() {
+            Ok(s)   => println!("stripe: {} algorithm {} sectors {} data {} parity {} devs {}",
+                k.k.p, s.algorithm, s.sectors, s.nr_data(), s.nr_redundant,
+                s.blocks.iter().map(|b| b.dev.to_string()).collect::<Vec<_>>().join(",")),
+            Err(e)  => ret = Err(e),
+        }
+    })?;
+    ret?;
+    Ok(())
+}
+
+fn journal_flush_times(fs: &Fs) -> anyhow::Result<()> {
+    /* Write, and flush, a journal entry to every device: */
+    errcode_to_result(unsafe { bcachefs::bch2_journal_meta(&mut (*fs.raw).journal) })?;
+
+    for (dev, t) in fs.journal_device_flush_times() {
+        println!("flushed: {} {}", dev,
+            t.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64());
+    }
+    Ok(())
+}
+
+fn recommend_prune(fs: &Fs, policy: PrunePolicy) -> anyhow::Result<()> {
+    for s in fs.recommend_prune(policy)? {
+        println!("prune: {}", s);
+    }
+    Ok(())
+}
+
+/* How long ago each bucket holding `path`'s data was last read, after reading
+ * `read` - the age is estimated from the read rate since the open: */
+fn read_age(fs: &Fs, path: &Path, read: Option<&Path>) -> anyhow::Result<()> {
+    if let Some(r) = read {
+        let inum = fs.lookup_path(r)?;
+        fs.file_hash(inum.subvol, inum.inum, HashAlgo::Crc32c)?;
+    }
+
+    let inum = fs.lookup_path(path)?;
+    let trans = BtreeTrans::new(fs);
+    let snapshot = subvol_snapshot(fs, inum.subvol)?;
+    let mut buckets = std::collections::BTreeSet::new();
+
+    trans.for_each_key(bcachefs::btree_id::BTREE_ID_extents,
+        spos(inum.inum, 0, snapshot), spos(inum.inum, u64::MAX, snapshot),
+        BtreeIterFlags::FILTER_SNAPSHOTS,
+        |k| {
+            for p in k.ptrs_decoded() {
+                let bucket_size = unsafe { (*(*fs.raw).devs[p.ptr.dev as usize]).mi.bucket_size } as u64;
+                buckets.insert((p.ptr.dev as u64, p.ptr.offset / bucket_size));
+            }
+            ControlFlow::Continue(())
+        })?;
+
+    for (dev, bucket) in buckets {
+        let a = trans.lockrestart_do(|| {
+            let mut iter = BtreeIter::new(&trans, bcachefs::btree_id::BTREE_ID_alloc,
+                spos(dev, bucket, 0), BtreeIterFlags::empty());
+
+            iter.peek_slot()?.as_alloc()
+        })?;
+
+        match a.read_age(fs) {
+            std::time::Duration::MAX    => println!("age: {} {} none", dev, bucket),
+            age                         => println!("age: {} {} {:.6}", dev, bucket, age.as_secs_f64()),
+        }
+    }
+    Ok(())
+}
+
+/* Verify a file's checksums, printing where on disk each bad replica is: */
+fn verify(fs: &Fs, path: &Path) -> anyhow::Result<()> {
+    let inum = fs.lookup_path(path)?;
+    let errors = fs.verify_inode(inum.inum)?;
+    let trans = BtreeTrans::new(fs);
+    let snapshot = subvol_snapshot(fs, inum.subvol)?;
+
+    for e in &errors {
+        let mut sector = None;
+
+        trans.for_each_key(bcachefs::btree_id::BTREE_ID_extents,
+            spos(inum.inum, (e.offset >> 9) + 1, snapshot), spos(inum.inum, u64::MAX, snapshot),
+            BtreeIterFlags::FILTER_SNAPSHOTS,
+            |k| {
+                sector = k.ptrs_decoded().iter()
+                    .find(|p| p.ptr.dev == e.dev)
+                    .map(|p| p.ptr.offset + p.crc.map_or(0, |crc| crc.offset as u64));
+                ControlFlow::Break(())
+            })?;
+
+        println!("bad: {} {} dev {} sector {}{}", e.offset, e.len, e.dev,
+            sector.map_or("none".to_string(), |s| s.to_string()),
+            if e.io_error { " io_error" } else { "" });
+    }
+    println!("errors: {}", errors.len());
+    Ok(())
+}
+
+fn list_mounted() -> anyhow::Result<()> {
+    for m in bch_bindgen::fs::list_mounted() {
+        println!("mounted: {} uuid {} devs {}", m.mountpoint.display(),
+            m.uuid.map_or("none".to_string(), |u| u.to_string()),
+            m.devices.iter().map(|d| d.display().to_string()).collect::<Vec<_>>().join(":"));
+    }
+    Ok(())
+}
+
+fn btree_cache_stats(fs: &Fs) -> anyhow::Result<()> {
+    let s = fs.btree_cache_stats();
+
+    println!("size: {}", s.size);
+    println!("used: {}", s.used);
+    println!("dirty: {}", s.dirty);
+    Ok(())
+}
+
+/* Log a truncate of `path` to `size` bytes, then stop as if we'd crashed: */
+fn log_truncate(fs: &Fs, path: &Path, size: u64) -> anyhow::Result<()> {
+    let inum = fs.lookup_path(path)?;
+
+    errcode_to_result(unsafe { bcachefs::bch2_file_truncate_log_only(fs.raw, inum, size) })?;
+    Ok(())
+}
+
+fn logged_ops(fs: &Fs) -> anyhow::Result<()> {
+    for op in fs.logged_ops()? {
+        if let Ok(t) = op.as_truncate() {
+            println!("truncate: subvol {} inum {} new_i_size {}", t.subvol, t.inum, t.new_i_size);
+        } else if let Ok(f) = op.as_finsert() {
+            println!("finsert: subvol {} inum {} src {} dst {} pos {} state {}",
+                f.subvol, f.inum, f.src_offset, f.dst_offset, f.pos, f.state);
+        } else {
+            println!("op: {} type {}", op.key().k.p, op.key().k.type_);
+        }
+    }
+    Ok(())
+}
+
+fn metadata_digest(fs: &Fs, path: &Path) -> anyhow::Result<()> {
+    let digest = fs.subvol_metadata_digest(fs.lookup_path(path)?.subvol)?;
+
+    println!("digest: {}", digest.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    Ok(())
+}
+
+/* Read `len` bytes of a file from `offset`, writing them to `out`: */
+fn read(fs: &Fs, path: &Path, offset: u64, len: usize, out: &Path) -> anyhow::Result<()> {
+    let inum = fs.lookup_path(path)?;
+    let mut buf = vec![0; len];
+    let n = fs.read_file(inum.subvol, inum.inum, offset, &mut buf)?;
+
+    std::fs::write(out, &buf[..n])?;
+    println!("read: {}", n);
+    Ok(())
+}
+
+/* Inodes created in the subvolume at `path` since it was last snapshotted: */
+fn added_inodes(fs: &Fs, path: &Path) -> anyhow::Result<()> {
+    let snapshot = subvol_snapshot(fs, fs.lookup_path(path)?.subvol)?;
+
+    for inum in fs.snapshot_added_inodes(snapshot)? {
+        println!("added: {}", inum);
+    }
+    Ok(())
+}
+
+fn target(fs: &Fs, which: TargetKind, set: Option<String>) -> anyhow::Result<()> {
+    if let Some(label) = set {
+        fs.set_target(which, &label)?;
+    }
+
+    let opts = fs.effective_options();
+    println!("target: {}", match which {
+        TargetKind::Foreground  => opts.foreground_target,
+        TargetKind::Background  => opts.background_target,
+        TargetKind::Promote     => opts.promote_target,
+    });
+    println!("rebalance_work: {}", fs.has_rebalance_work()?);
+    Ok(())
+}
+
+fn inode_types(fs: &Fs) -> anyhow::Result<()> {
+    for (t, nr) in fs.inode_type_histogram()? {
+        println!("inodes: {:?} {}", t, nr);
+    }
+    Ok(())
+}
+
+fn encryption(fs: &Fs) -> anyhow::Result<()> {
+    match fs.encryption() {
+        Some(e) => println!("encryption: {:?} kdf {:?}", e.algo, e.key_derivation),
+        None    => println!("encryption: none"),
+    }
+    Ok(())
+}
+
+fn discard(fs: &Fs, dev: Option<u32>) -> anyhow::Result<()> {
+    println!("discarded: {}", fs.discard_free_space(dev)?);
+    Ok(())
+}
+
+fn extents_on_device(fs: &Fs, dev: u32) -> anyhow::Result<()> {
+    for (inode, offset) in fs.extents_on_device(dev)? {
+        println!("extent: {} {}", inode, offset << 9);
+    }
+    Ok(())
+}
+
+fn extents(fs: &Fs, path: &Path) -> anyhow::Result<()> {
+    let inum = fs.lookup_path(path)?;
+    let trans = BtreeTrans::new(fs);
+    let snapshot = trans.lockrestart_do(|| trans.subvol_snapshot(inum.subvol))?;
+
+    trans.for_each_key(bcachefs::btree_id::BTREE_ID_extents,
+        spos(inum.inum, 0, snapshot), spos(inum.inum, u64::MAX, snapshot),
+        BtreeIterFlags::FILTER_SNAPSHOTS,
+        |k| {
+            let (end, size) = (k.k.p.offset, k.k.size as u64);
+            let devs: Vec<_> = k.ptrs_decoded().iter()
+                .map(|p| p.ptr.dev.to_string())
+                .collect();
+
+            println!("extent: {} {} devs {}", (end - size) << 9, size << 9, devs.join(","));
+            ControlFlow::Continue(())
+        })?;
+    Ok(())
+}
+
+/// A device file that fails reads overlapping the given byte ranges, for
+/// injecting read errors
+struct FailReads {
+    file:   File,
+    size:   u64,
+    bad:    Vec<(u64, u64)>,
+}
+
+impl DeviceBackend for FailReads {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let end = offset + buf.len() as u64;
+
+        if self.bad.iter().any(|&(start, bad_end)| offset < bad_end && start < end) {
+            return Err(std::io::Error::from_raw_os_error(libc::EIO));
+        }
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+        self.file.write_at(buf, offset)
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// A device image held in memory
+struct MemImage {
+    data:   Mutex<Vec<u8>>,
+    size:   u64,
+}
+
+impl DeviceBackend for MemImage {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let start = std::cmp::min(offset as usize, data.len());
+        let n = std::cmp::min(buf.len(), data.len() - start);
+
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+        let mut data = self.data.lock().unwrap();
+        let start = std::cmp::min(offset as usize, data.len());
+        let n = std::cmp::min(buf.len(), data.len() - start);
+
+        data[start..start + n].copy_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/* Open a copy of the filesystem in memory; the devices aren't modified: */
+fn open_in_memory(devices: &Vec<PathBuf>, opts: bcachefs::bch_opts) -> anyhow::Result<Fs> {
+    let backends = devices.iter()
+        .map(|dev| {
+            let data = std::fs::read(dev)?;
+            let size = data.len() as u64;
+
+            Ok(Box::new(MemImage { data: Mutex::new(data), size }) as Box<dyn DeviceBackend>)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(Fs::open_with_backends(backends, opts)?)
+}
+
+/* Open the filesystem with reads of the data of file `path` failing: */
+fn open_failing_reads(devices: &Vec<PathBuf>, opts: bcachefs::bch_opts, path: &Path) -> anyhow::Result<Fs> {
+    let ranges: Vec<_> = {
+        let fs = Fs::open(devices, opts)?;
+        let inum = fs.lookup_path(path)?;
+
+        fs.physical_map()?
+            .filter(|r| r.inode == inum.inum)
+            .map(|r| (r.dev, r.offset << 9, (r.offset + r.len) << 9))
+            .collect()
+    };
+
+    let backends = devices.iter()
+        .map(|dev| {
+            let dev_idx = Device::read_super(dev)?.dev_idx;
+            let mut file = OpenOptions::new().read(true).write(true).open(dev)?;
+            let size = file.seek(SeekFrom::End(0))?;
+            let bad = ranges.iter()
+                .filter(|r| r.0 == dev_idx)
+                .map(|r| (r.1, r.2))
+                .collect();
+
+            Ok(Box::new(FailReads { file, size, bad }) as Box<dyn DeviceBackend>)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(Fs::open_with_backends(backends, opts)?)
+}
+
+fn parse_bkey_type(s: &str) -> Result<bcachefs::bch_bkey_type, String> {
+    let name = std::ffi::CString::new(s).map_err(|_| format!("invalid key type {:?}", s))?;
+    let v = unsafe {
+        bcachefs::match_string(bcachefs::bch2_bkey_types[..].as_ptr(), (-(1 as isize)) as usize, name.as_ptr())
+    };
+
+    if v >= 0 {
+        Ok(unsafe { std::mem::transmute(v as u32) })
+    } else {
+        Err(format!("invalid key type {:?}", s))
+    }
+}
+
+fn parse_device_state(s: &str) -> Result<DeviceState, String> {
+    match s {
+        "rw"        => Ok(DeviceState::BCH_MEMBER_STATE_rw),
+        "ro"        => Ok(DeviceState::BCH_MEMBER_STATE_ro),
+        "failed"    => Ok(DeviceState::BCH_MEMBER_STATE_failed),
+        "spare"     => Ok(DeviceState::BCH_MEMBER_STATE_spare),
+        _           => Err(format!("invalid device state {:?}", s)),
+    }
+}
+
+fn device_state_str(state: DeviceState) -> &'static str {
+    match state {
+        DeviceState::BCH_MEMBER_STATE_rw        => "rw",
+        DeviceState::BCH_MEMBER_STATE_ro        => "ro",
+        DeviceState::BCH_MEMBER_STATE_failed    => "failed",
+        DeviceState::BCH_MEMBER_STATE_spare     => "spare",
+        _                                       => "invalid",
+    }
+}
+
+fn parse_target_kind(s: &str) -> Result<TargetKind, String> {
+    match s {
+        "foreground"    => Ok(TargetKind::Foreground),
+        "background"    => Ok(TargetKind::Background),
+        "promote"       => Ok(TargetKind::Promote),
+        _               => Err(format!("invalid target {:?}", s)),
+    }
+}
+
+fn parse_hash_algo(s: &str) -> Result<HashAlgo, String> {
+    match s {
+        "crc32c"    => Ok(HashAlgo::Crc32c),
+        "crc64"     => Ok(HashAlgo::Crc64),
+        "xxhash"    => Ok(HashAlgo::Xxhash),
+        _           => Err(format!("invalid hash algorithm {:?}", s)),
+    }
+}
+
+fn parse_error_action(s: &str) -> Result<ErrorAction, String> {
+    match s {
+        "continue"  => Ok(ErrorAction::Continue),
+        "ro"        => Ok(ErrorAction::ReadOnly),
+        "panic"     => Ok(ErrorAction::Panic),
+        _           => Err(format!("invalid error action {:?}", s)),
+    }
+}
+
+fn error_action_str(action: ErrorAction) -> &'static str {
+    match action {
+        ErrorAction::Continue   => "continue",
+        ErrorAction::ReadOnly   => "ro",
+        ErrorAction::Panic      => "panic",
+    }
+}
+
+fn error_action(fs: &Fs, actions: Vec<ErrorAction>) -> anyhow::Result<()> {
+    println!("errors: {}", error_action_str(fs.error_action()));
+
+    for action in actions {
+        fs.set_error_action(action)?;
+        println!("errors: {}", error_action_str(fs.error_action()));
+    }
+
+    Ok(())
+}
+
+fn hexdump(fs: &Fs, btree: bcachefs::btree_id) -> anyhow::Result<()> {
+    let trans = BtreeTrans::new(fs);
+
+    trans.for_each_key(btree, POS_MIN, SPOS_MAX, BtreeIterFlags::ALL_SNAPSHOTS, |k| {
+        let p = k.k.p;
+
+        println!("key: {}", p);
+        print!("{}", k.hexdump());
+        ControlFlow::Continue(())
+    })?;
+    Ok(())
+}
+
+fn sample_nodes(fs: &Fs, btree: bcachefs::btree_id, max_nodes: usize) -> anyhow::Result<()> {
+    let nodes = fs.sample_nodes(btree, max_nodes)?;
+
+    for n in &nodes {
+        println!("node: {} {}-{} keys {} fill {:.3}",
+            n.level, n.min_key, n.max_key, n.nr_keys, n.fill);
+    }
+    println!("nodes: {}", nodes.len());
+    Ok(())
+}
+
+#[derive(Subcommand, Debug)]
+enum Op {
+    /// Create a regular file, and write SIZE bytes of data to it at OFFSET
+    Create {
+        path:       PathBuf,
+        #[arg(long, default_value_t = 0)]
+        size:       usize,
+        #[arg(long, default_value_t = 0)]
+        offset:     u64,
+        /// Files created with the same seed and size have the same contents
+        #[arg(long, default_value_t = 0)]
+        seed:       u8,
+        /// Only allocate the data on this device
+        #[arg(long)]
+        dev:        Option<u32>,
+    },
+    /// Create a directory
+    Mkdir {
+        path:       PathBuf,
+    },
+    /// Create a symlink, without a target
+    Symlink {
+        path:       PathBuf,
+    },
+    /// Unlink a file or empty directory
+    Rm {
+        path:       PathBuf,
+    },
+    /// Create a hardlink to TARGET at PATH
+    Link {
+        target:     PathBuf,
+        path:       PathBuf,
+    },
+    /// Print filesystem capacity, used and free space, in sectors
+    Usage,
+    /// Create a subvolume
+    Subvol {
+        path:       PathBuf,
+    },
+    /// Create a snapshot of the subvolume at SRC
+    Snapshot {
+        src:        PathBuf,
+        path:       PathBuf,
+    },
+    /// List the snapshots btree: id, parent, children and subvolume
+    Snapshots,
+    /// Print whether each device has user data
+    HasUserData,
+    /// Print the superblock's feature flags, and whether they're supported
+    Features,
+    /// Run full mark and sweep gc, and print the space it reclaimed; needs
+    /// --fix-errors to free leaked space
+    Gc,
+    /// Sum the accounting contributions of the extents btree's keys, and print
+    /// them next to the user data accounted to devices, in sectors
+    AccountingSum,
+    /// Delete the keys in a btree from START to END - the end is exclusive for
+    /// extents btrees
+    DeleteRange {
+        #[arg(short, long)]
+        btree:      bcachefs::btree_id,
+        start:      bcachefs::bpos,
+        end:        bcachefs::bpos,
+        /// Don't run triggers: space the keys referenced is leaked, until gc
+        #[arg(long)]
+        no_triggers: bool,
+    },
+    /// List the freespace btree's extents: start and number of buckets
+    Freespace,
+    /// Check and repair alloc info, as fsck would; needs --fix-errors to
+    /// repair
+    RecalcAlloc,
+    /// Print the type of an inode in the root subvolume
+    InodeType {
+        path:       PathBuf,
+    },
+    /// Print the position of each key in a btree, stopping at the first key at
+    /// or after UNTIL
+    VisitKeys {
+        #[arg(short, long)]
+        btree:      bcachefs::btree_id,
+        #[arg(long, default_value = "SPOS_MAX")]
+        until:      bcachefs::bpos,
+    },
+    /// Print an inode's fields
+    Inode {
+        path:       PathBuf,
+    },
+    /// Peek at the start of a btree, and print the locks the transaction then
+    /// holds: btree, level and type
+    HeldLocks {
+        #[arg(short, long)]
+        btree:      bcachefs::btree_id,
+        #[arg(long)]
+        intent:     bool,
+    },
+    /// Print the hash of a file's contents
+    Hash {
+        path:       PathBuf,
+        #[arg(long, default_value = "crc32c", value_parser = parse_hash_algo)]
+        algo:       HashAlgo,
+    },
+    /// Print the filesystem label, after optionally setting it
+    Label {
+        #[arg(long)]
+        set:        Option<String>,
+    },
+    /// Write a send stream of the subvolume at PATH to OUT, optionally
+    /// relative to the older snapshot at PARENT
+    Send {
+        path:       PathBuf,
+        out:        PathBuf,
+        #[arg(long)]
+        parent:     Option<PathBuf>,
+    },
+    /// Apply a send stream, creating a new subvolume in directory INTO
+    Receive {
+        stream:     PathBuf,
+        #[arg(long, default_value = "/")]
+        into:       PathBuf,
+    },
+    /// Print the position and size of each key in a btree from START to END
+    Scan {
+        #[arg(short, long)]
+        btree:      bcachefs::btree_id,
+        #[arg(long, default_value = "POS_MIN")]
+        start:      bcachefs::bpos,
+        #[arg(long, default_value = "SPOS_MAX")]
+        end:        bcachefs::bpos,
+        #[arg(long)]
+        reverse:    bool,
+    },
+    /// List the holes in a file in the root subvolume: offset and length
+    Holes {
+        path:       PathBuf,
+    },
+    /// Set the compression type, e.g. "zstd" or "lz4:3", for this open
+    SetCompression {
+        compression: String,
+        #[arg(long)]
+        background: bool,
+    },
+    /// Print the number of entries in a directory in the root subvolume
+    DirentCount {
+        path:       PathBuf,
+    },
+    /// Run rebalance until there's no work left, or for at most TIMEOUT
+    /// seconds, printing progress
+    Rebalance {
+        #[arg(long, default_value_t = 60)]
+        timeout:    u64,
+    },
+    /// Print the keys in a btree grouped by inode: inode number, then the
+    /// positions of its keys
+    GroupByInode {
+        #[arg(short, long)]
+        btree:      bcachefs::btree_id,
+    },
+    /// List the checksums stored in a file's extents: offset, device and
+    /// checksum type and value
+    Checksums {
+        path:       PathBuf,
+    },
+    /// Print whether two paths are hardlinks to the same inode
+    SameInode {
+        a:          PathBuf,
+        b:          PathBuf,
+    },
+    /// List the keys of a type, e.g. "dirent", in every btree that has them:
+    /// btree and position
+    ScanType {
+        #[arg(value_parser = parse_bkey_type)]
+        ty:         bcachefs::bch_bkey_type,
+    },
+    /// Print the iterator flags for a plain read of a btree
+    ReadFlags {
+        #[arg(short, long)]
+        btree:      bcachefs::btree_id,
+    },
+    /// Print when the filesystem was created and last mounted, in seconds
+    /// since the epoch
+    Times,
+    /// Print a btree's depth, and the minimum, average and maximum fill of a
+    /// sample of its leaf nodes
+    BtreeHealth {
+        #[arg(short, long)]
+        btree:      bcachefs::btree_id,
+    },
+    /// Rewrite the leaf node of a btree containing POS
+    RewriteNode {
+        #[arg(short, long)]
+        btree:      bcachefs::btree_id,
+        #[arg(default_value = "POS_MIN")]
+        pos:        bcachefs::bpos,
+    },
+    /// Print the data each subvolume has to itself, and shares with other
+    /// snapshots, in bytes
+    SnapshotUsage,
+    /// Look up the key at POS normally, then twice through the key cache,
+    /// printing the key and the number of keys in the key cache
+    Lookup {
+        #[arg(short, long)]
+        btree:      bcachefs::btree_id,
+        pos:        bcachefs::bpos,
+    },
+    /// Print whether each device has a bcachefs superblock, without opening
+    /// the filesystem
+    IsBcachefs,
+    /// Print the superblock of each device without opening the filesystem:
+    /// uuid, index and number of devices
+    ReadSuper,
+    /// Set an inode's link count from the dirents pointing to it, printing the
+    /// old and new link counts
+    FixNlink {
+        path:       PathBuf,
+    },
+    /// Print a device's buckets and sectors of each data type, from the alloc
+    /// btree and from the in-memory accounting
+    DevAccounting {
+        #[arg(default_value_t = 0)]
+        dev:        u32,
+    },
+    /// Print a device's state, after optionally setting it to rw, ro, failed
+    /// or spare
+    DevState {
+        dev:        u32,
+        #[arg(long, value_parser = parse_device_state)]
+        set:        Option<DeviceState>,
+    },
+    /// List where on disk each extent's data is, in sectors
+    PhysicalMap,
+    /// Wait for at least SECTORS to be free, for at most TIMEOUT milliseconds
+    WaitFreeSpace {
+        sectors:    u64,
+        #[arg(long, default_value_t = 10000)]
+        timeout:    u64,
+    },
+    /// Peek at the start of a btree, then list the btree node cache: btree,
+    /// level and max key of each node
+    CacheContents {
+        #[arg(short, long)]
+        btree:      bcachefs::btree_id,
+    },
+    /// Reflink SECTORS of SRC starting at sector SRC_OFFSET into DST at sector
+    /// DST_OFFSET; by default, the rest of SRC
+    Reflink {
+        src:        PathBuf,
+        dst:        PathBuf,
+        #[arg(long, default_value_t = 0)]
+        src_offset: u64,
+        #[arg(long, default_value_t = 0)]
+        dst_offset: u64,
+        #[arg(long)]
+        sectors:    Option<u64>,
+    },
+    /// List a file's reflink pointers: offset and size in sectors, the offset
+    /// in the reflink btree they point to, and their padding
+    ReflinkPtrs {
+        path:       PathBuf,
+    },
+    /// Print the options set on the root of the subvolume at PATH; --set
+    /// NAME=VALUE sets one first, and --set NAME unsets it
+    SubvolOptions {
+        path:       PathBuf,
+        #[arg(long)]
+        set:        Option<String>,
+    },
+    /// Print a rough estimate of how long a full fsck would take
+    FsckEstimate,
+    /// List the erasure coded stripes: algorithm, sectors per block, number
+    /// of data and parity blocks, and the device of each block
+    Stripes,
+    /// Flush the journal, then print when each device last had a journal
+    /// flush: device index and seconds since the epoch
+    JournalFlushTimes,
+    /// List the snapshots a retention policy would delete
+    RecommendPrune {
+        #[arg(long, default_value_t = 0)]
+        keep_last:      usize,
+        #[arg(long, default_value_t = 0)]
+        keep_daily:     usize,
+        #[arg(long, default_value_t = 0)]
+        keep_weekly:    usize,
+    },
+    /// Print how long ago each bucket holding a file's data was last read:
+    /// device, bucket and seconds, or none if nothing's been read yet. --read
+    /// reads another file first.
+    ReadAge {
+        path:       PathBuf,
+        #[arg(long)]
+        read:       Option<PathBuf>,
+    },
+    /// Verify the checksums of a file in the root subvolume, reading every
+    /// replica: prints the offset and length of each bad extent, and the
+    /// device and sector of the bad replica
+    Verify {
+        path:       PathBuf,
+    },
+    /// List the bcachefs filesystems the kernel has mounted: mountpoint, uuid
+    /// and member devices. Doesn't take any devices.
+    ListMounted,
+    /// Print the btree node cache's size bound, and how much of it is used
+    /// and dirty, in bytes
+    BtreeCacheStats,
+    /// Log a truncate of a file to SIZE bytes without doing it, as if we
+    /// crashed right after; recovery finishes it
+    LogTruncate {
+        path:       PathBuf,
+        size:       u64,
+    },
+    /// List the logged operations recovery has yet to finish - so, opened
+    /// with --norecovery
+    LoggedOps,
+    /// Print the digest of the metadata of the subvolume at PATH
+    MetadataDigest {
+        path:       PathBuf,
+    },
+    /// Read LEN bytes of a file starting at byte OFFSET, writing them to OUT
+    Read {
+        path:       PathBuf,
+        #[arg(long, default_value_t = 0)]
+        offset:     u64,
+        #[arg(long)]
+        len:        usize,
+        #[arg(long)]
+        out:        PathBuf,
+    },
+    /// List the inodes in the current snapshot of the subvolume at PATH that
+    /// aren't in its parent snapshot
+    AddedInodes {
+        path:       PathBuf,
+    },
+    /// Print a data placement target - foreground, background or promote -
+    /// after optionally setting it to a disk group or device, and whether
+    /// there's rebalance work
+    Target {
+        #[arg(value_parser = parse_target_kind)]
+        which:      TargetKind,
+        #[arg(long)]
+        set:        Option<String>,
+    },
+    /// Print the number of inodes of each type, over every snapshot
+    InodeTypes,
+    /// Print the data encryption algorithm and key derivation function, or
+    /// none if the filesystem isn't encrypted
+    Encryption,
+    /// Discard the free space on device DEV, or on every device, printing the
+    /// number of sectors discarded
+    Discard {
+        dev:        Option<u32>,
+    },
+    /// List the extents with data on device DEV, from the backpointers btree:
+    /// inode and start offset; reflinked extents are in inode 0
+    ExtentsOnDevice {
+        dev:        u32,
+    },
+    /// List a file's extents: offset, length, and the devices of their pointers
+    Extents {
+        path:       PathBuf,
+    },
+    /// Print the error action, then set it to each of ACTIONS in turn and
+    /// print it back
+    ErrorAction {
+        #[arg(value_parser = parse_error_action)]
+        actions:    Vec<ErrorAction>,
+    },
+    /// Hexdump the value of every key in a btree
+    Hexdump {
+        #[arg(short, long)]
+        btree:      bcachefs::btree_id,
+    },
+    /// Print fill statistics of up to MAX_NODES leaf nodes sampled across a
+    /// btree
+    SampleNodes {
+        #[arg(short, long)]
+        btree:      bcachefs::btree_id,
+        max_nodes:  usize,
+    },
+}
+
+/// Run library operations on an offline filesystem, printing the results as
+/// "name: value" lines - for testing
+#[derive(Parser, Debug)]
+pub struct Cli {
+    /// Filesystem devices; required unless --for-path is given, or the
+    /// operation doesn't open a filesystem
+    #[arg(short, long = "dev")]
+    devices:    Vec<PathBuf>,
+
+    /// Open the mounted filesystem containing this path, without changing it,
+    /// instead of DEVICES
+    #[arg(long, conflicts_with_all = ["devices", "fail_reads_of", "in_memory", "recovery_progress",
+                                      "btree_cache_size"])]
+    for_path:   Option<PathBuf>,
+
+    /// Make reads of this file's data fail with EIO
+    #[arg(long)]
+    fail_reads_of: Option<PathBuf>,
+
+    /// Run against a copy of the devices loaded into memory, leaving the
+    /// devices unmodified
+    #[arg(long, conflicts_with = "fail_reads_of")]
+    in_memory:  bool,
+
+    /// Print each recovery pass as it's run while opening
+    #[arg(long, conflicts_with_all = ["fail_reads_of", "in_memory"])]
+    recovery_progress: bool,
+
+    /// Bound the btree node cache to this many bytes
+    #[arg(long, conflicts_with_all = ["fail_reads_of", "in_memory"])]
+    btree_cache_size: Option<u64>,
+
+    /// Run the fsck recovery passes
+    #[arg(long)]
+    fsck:       bool,
+
+    /// Open read only without running recovery, or replaying the journal
+    #[arg(long, conflicts_with = "fsck")]
+    norecovery: bool,
+
+    /// Fix errors found without asking
+    #[arg(long)]
+    fix_errors: bool,
+
+    #[command(subcommand)]
+    op:         Op,
+}
+
+fn cmd_debug_inner(opt: Cli) -> anyhow::Result<()> {
+    let mut fs_opts: bcachefs::bch_opts = Default::default();
+
+    if opt.for_path.is_some() {
+        /* The kernel has the devices open: */
+        opt_set!(fs_opts, noexcl,       1);
+        opt_set!(fs_opts, nochanges,    1);
+    }
+
+    if opt.fsck {
+        opt_set!(fs_opts, fsck,         1);
+    }
+
+    if opt.norecovery {
+        opt_set!(fs_opts, norecovery,   1);
+        opt_set!(fs_opts, nochanges,    1);
+    }
+
+    if opt.fix_errors {
+        opt_set!(fs_opts, fix_errors,   bcachefs::fsck_err_opts::FSCK_FIX_yes as u8);
+    }
+
+    match opt.op {
+        Op::IsBcachefs  => return is_bcachefs(&opt.devices),
+        Op::ReadSuper   => return read_super(&opt.devices),
+        Op::ListMounted => return list_mounted(),
+        _               => {},
+    }
+
+    if opt.devices.is_empty() && opt.for_path.is_none() {
+        return Err(anyhow!("no devices given"));
+    }
+
+    let fs = match (&opt.for_path, &opt.fail_reads_of) {
+        (Some(path), _) => Fs::open_for_path(path, fs_opts)?,
+        (_, Some(path)) => open_failing_reads(&opt.devices, fs_opts, path)?,
+        _ if opt.in_memory => open_in_memory(&opt.devices, fs_opts)?,
+        _ if opt.recovery_progress || opt.btree_cache_size.is_some() => {
+            let mut o = FsOpenOptions::new(fs_opts);
+
+            if opt.recovery_progress {
+                o = o.on_recovery_progress(|pass, done, total| {
+                    println!("recovery: {} {} {}", pass.name(), done, total);
+                });
+            }
+            if let Some(bytes) = opt.btree_cache_size {
+                o = o.btree_cache_size(bytes);
+            }
+            o.open(&opt.devices)?
+        }
+        _               => Fs::open(&opt.devices, fs_opts)?,
+    };
+
+    match opt.op {
+        Op::Create { path, size, offset, seed, dev } => create(&fs, &path, size, offset, seed, dev),
+        Op::Mkdir { path }          => mknod(&fs, &path, libc::S_IFDIR|0o755),
+        Op::Symlink { path }        => mknod(&fs, &path, libc::S_IFLNK|0o777),
+        Op::Rm { path }             => unlink(&fs, &path),
+        Op::Link { target, path }   => link(&fs, &target, &path),
+        Op::Usage                   => usage(&fs),
+        Op::Subvol { path }         => create_subvol(&fs, &path, None),
+        Op::Snapshot { src, path }  => create_subvol(&fs, &path, Some(&src)),
+        Op::Snapshots               => snapshots(&fs),
+        Op::HasUserData             => has_user_data(&fs),
+        Op::Features                => features(&fs),
+        Op::Gc                      => gc(&fs),
+        Op::AccountingSum           => accounting_sum(&fs),
+        Op::DeleteRange { btree, start, end, no_triggers } =>
+            delete_range(&fs, btree, start, end, no_triggers),
+        Op::Freespace               => freespace(&fs),
+        Op::RecalcAlloc             => recalc_alloc(&fs),
+        Op::InodeType { path }      => inode_type(&fs, &path),
+        Op::VisitKeys { btree, until } => visit_keys(&fs, btree, until),
+        Op::Inode { path }          => inode(&fs, &path),
+        Op::HeldLocks { btree, intent } => held_locks(&fs, btree, intent),
+        Op::Hash { path, algo }     => file_hash(&fs, &path, algo),
+        Op::Label { set }           => label(&fs, set),
+        Op::Send { path, out, parent } => send(&fs, &path, parent.as_deref(), &out),
+        Op::Receive { stream, into } => receive(&fs, &stream, &into),
+        Op::Scan { btree, start, end, reverse } => scan(&fs, btree, start, end, reverse),
+        Op::Holes { path }          => holes(&fs, &path),
+        Op::SetCompression { compression, background } => set_compression(&fs, &compression, background),
+        Op::DirentCount { path }    => dirent_count(&fs, &path),
+        Op::Rebalance { timeout }   => rebalance(&fs, timeout),
+        Op::GroupByInode { btree }  => group_by_inode(&fs, btree),
+        Op::Checksums { path }      => checksums(&fs, &path),
+        Op::SameInode { a, b }      => same_inode(&fs, &a, &b),
+        Op::ScanType { ty }         => scan_type(&fs, ty),
+        Op::ReadFlags { btree }     => read_flags(btree),
+        Op::Times                   => times(&fs),
+        Op::BtreeHealth { btree }   => btree_health(&fs, btree),
+        Op::RewriteNode { btree, pos } => rewrite_node(&fs, btree, pos),
+        Op::SnapshotUsage           => snapshot_usage(&fs),
+        Op::Lookup { btree, pos }   => lookup(&fs, btree, pos),
+        Op::FixNlink { path }       => fix_nlink(&fs, &path),
+        Op::DevAccounting { dev }   => dev_accounting(&fs, dev),
+        Op::DevState { dev, set }  => dev_state(&fs, dev, set),
+        Op::PhysicalMap             => physical_map(&fs),
+        Op::WaitFreeSpace { sectors, timeout } => wait_free_space(&fs, sectors, timeout),
+        Op::CacheContents { btree } => cache_contents(&fs, btree),
+        Op::Reflink { src, dst, src_offset, dst_offset, sectors } =>
+            reflink(&fs, &src, &dst, src_offset, dst_offset, sectors),
+        Op::ReflinkPtrs { path }    => reflink_ptrs(&fs, &path),
+        Op::SubvolOptions { path, set } => subvol_options(&fs, &path, set),
+        Op::FsckEstimate            => fsck_estimate(&fs),
+        Op::Stripes                 => stripes(&fs),
+        Op::JournalFlushTimes       => journal_flush_times(&fs),
+        Op::RecommendPrune { keep_last, keep_daily, keep_weekly } =>
+            recommend_prune(&fs, PrunePolicy { keep_last, keep_daily, keep_weekly }),
+        Op::ReadAge { path, read }  => read_age(&fs, &path, read.as_deref()),
+        Op::Verify { path }         => verify(&fs, &path),
+        Op::BtreeCacheStats         => btree_cache_stats(&fs),
+        Op::LogTruncate { path, size } => log_truncate(&fs, &path, size),
+        Op::LoggedOps               => logged_ops(&fs),
+        Op::MetadataDigest { path } => metadata_digest(&fs, &path),
+        Op::Read { path, offset, len, out } => read(&fs, &path, offset, len, &out),
+        Op::AddedInodes { path }    => added_inodes(&fs, &path),
+        Op::Target { which, set }   => target(&fs, which, set),
+        Op::InodeTypes              => inode_types(&fs),
+        Op::Encryption              => encryption(&fs),
+        Op::Discard { dev }         => discard(&fs, dev),
+        Op::ExtentsOnDevice { dev } => extents_on_device(&fs, dev),
+        Op::Extents { path }        => extents(&fs, &path),
+        Op::IsBcachefs | Op::ReadSuper | Op::ListMounted => unreachable!(),
+        Op::ErrorAction { actions } => error_action(&fs, actions),
+        Op::Hexdump { btree }       => hexdump(&fs, btree),
+        Op::SampleNodes { btree, max_nodes } => sample_nodes(&fs, btree, max_nodes),
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn cmd_debug(argc: c_int, argv: *const *const c_char) -> c_int {
+    transform_c_args!(argv, argc, argv);
+    let opt = Cli::parse_from(argv);
+
+    log::set_boxed_logger(Box::new(SimpleLogger)).unwrap();
+    log::set_max_level(LevelFilter::Warn);
+    colored::control::set_override(atty::is(Stream::Stdout));
+
+    if let Err(e) = cmd_debug_inner(opt) {
+        error!("Fatal error: {}", e);
+        1
+    } else {
+        0
+    }
+}
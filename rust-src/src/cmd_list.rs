@@ -13,17 +13,32 @@ use std::ffi::{c_int, c_char};
 use crate::transform_c_args;
 
 fn list_keys(fs: &Fs, opt: Cli) -> anyhow::Result<()> {
+    let start = opt.after.map_or(opt.start, |after| std::cmp::max(opt.start, after));
+
     let trans = BtreeTrans::new(fs);
-    let mut iter = BtreeIter::new(&trans, opt.btree, opt.start,
+    let mut iter = BtreeIter::new(&trans, opt.btree, start,
         BtreeIterFlags::ALL_SNAPSHOTS|
         BtreeIterFlags::PREFETCH);
+    let mut nr = 0;
+    let mut last = None;
 
     while let Some(k) = iter.peek_and_restart()? {
         if k.k.p > opt.end {
             break;
         }
 
-        println!("{}", k.to_text(fs));
+        if opt.after.map_or(true, |after| k.k.p > after) {
+            if opt.limit == Some(nr) {
+                if let Some(last) = last {
+                    println!("next: {}", last);
+                }
+                break;
+            }
+
+            println!("{}", k.to_text(fs));
+            last = Some(k.k.p);
+            nr += 1;
+        }
         iter.advance();
     }
 
@@ -114,6 +129,15 @@ pub struct Cli {
     #[arg(short, long, default_value="keys")]
     mode:       Mode,
 
+    /// Stop after listing this many keys, and print the position to pass to
+    /// --after to continue
+    #[arg(long)]
+    limit:      Option<usize>,
+
+    /// Only list keys after this position (exclusive), for paging with --limit
+    #[arg(long)]
+    after:      Option<bcachefs::bpos>,
+
     /// Check (fsck) the filesystem first
     #[arg(short, long, default_value_t=false)]
     fsck:       bool,
@@ -5,6 +5,7 @@ pub mod logger;
 pub mod cmd_mount;
 pub mod cmd_list;
 pub mod cmd_completions;
+pub mod cmd_debug;
 
 #[derive(clap::Parser, Debug)]
 #[command(name = "bcachefs")]
@@ -18,6 +19,7 @@ enum Subcommands {
     List(cmd_list::Cli),
     Mount(cmd_mount::Cli),
     Completions(cmd_completions::Cli),
+    Debug(cmd_debug::Cli),
 }
 
 #[macro_export]
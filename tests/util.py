@@ -1,5 +1,6 @@
 #!/usr/bin/python3
 
+import ctypes
 import errno
 import os
 import re
@@ -92,6 +93,37 @@ def format_1g(tmpdir):
     run_bch('format', dev, check=True)
     return dev
 
+def bcachefs_mounts():
+    """Mountpoints of the bcachefs filesystems mounted by the kernel."""
+    with open('/proc/mounts') as f:
+        return [l.split()[1] for l in f if l.split()[2] == 'bcachefs']
+
+def supports_punch_hole(lpath):
+    """Whether files in directory lpath can have holes punched in them - how
+    discards are done on images in files."""
+    FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE = 1, 2
+    libc = ctypes.CDLL(None, use_errno=True)
+
+    with tempfile.TemporaryFile(dir=lpath) as f:
+        f.write(bytes(8192))
+        f.flush()
+        return libc.fallocate(f.fileno(), FALLOC_FL_KEEP_SIZE|FALLOC_FL_PUNCH_HOLE,
+                              ctypes.c_longlong(0), ctypes.c_longlong(4096)) == 0
+
+def run_debug(devs, *args, **kwargs):
+    """Run a 'bcachefs debug' operation on an offline filesystem, on one
+    device or a list of them."""
+    if not isinstance(devs, list):
+        devs = [devs]
+
+    dev_args = [a for dev in devs for a in ('-d', dev)]
+    return run_bch('debug', *dev_args, *args, **kwargs)
+
+def debug_values(stdout, name):
+    """Values of the "name: value" lines printed by 'bcachefs debug'."""
+    prefix = name + ': '
+    return [l[len(prefix):] for l in stdout.splitlines() if l.startswith(prefix)]
+
 def mountpoint(tmpdir):
     """Construct a mountpoint "mnt" for tests."""
     path = Path(tmpdir) / 'mnt'